@@ -1,18 +1,57 @@
+mod notifier;
+use notifier::Notifier;
 mod owned;
 pub use owned::*;
+mod pool;
+pub use pool::RecordPool;
+#[cfg(feature = "futures")]
+mod stream;
+#[cfg(feature = "futures")]
+use stream::SenderWaker;
+#[cfg(all(feature = "capture", unix))]
+#[cfg_attr(feature = "nightly", doc(cfg(all(feature = "capture", unix))))]
+pub mod capture;
+#[cfg(feature = "child")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "child")))]
+pub mod child;
+#[cfg(feature = "json")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "json")))]
+pub mod json;
+#[cfg(feature = "log")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "log")))]
+pub mod log_bridge;
+pub mod panic_hook;
+#[cfg(feature = "socket")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "socket")))]
+pub mod socket;
+#[cfg(feature = "syslog")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "syslog")))]
+pub mod syslog;
+#[cfg(feature = "tail")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "tail")))]
+pub mod tail;
+#[cfg(any(feature = "json", feature = "syslog"))]
+mod timestamp;
+#[cfg(feature = "tracing")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "tracing")))]
+pub mod tracing_layer;
 
 use crossbeam_channel::Sender;
 use slog::{Record, KV};
-use std::fmt;
+use std::{
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 pub struct Drain {
-    tx: Sender<OwnedRecord>,
+    data: DrainData,
 }
 
 impl Drain {
     #[inline]
     pub fn new(data: DrainData) -> Self {
-        Drain { tx: data.0 }
+        Drain { data }
     }
 }
 
@@ -32,38 +71,114 @@ impl slog::Drain for Drain {
         record: &Record,
         logger_values: &slog::OwnedKVList,
     ) -> Result<Self::Ok, Self::Err> {
-        let mut ser = ToOwnedSerializer(OwnedKVList(Vec::new()));
+        let mut buffers = self.data.pool.take();
+        let mut ser = ToOwnedSerializer(OwnedKVList(buffers.kv), buffers.strs);
         record
             .kv()
             .serialize(record, &mut ser)
             .map_err(Error::Serialization)?;
-        self.tx
-            .send(OwnedRecord {
-                msg: fmt::format(*record.msg()),
-                location: *record.location(),
-                tag: record.tag().to_string(),
-                level: record.level(),
-                kv: ser.0,
-                logger_values: logger_values.clone(),
-            })
-            .map_err(|_| Error::Send)
+        let _ = buffers.msg.write_fmt(*record.msg());
+        buffers.tag.push_str(record.tag());
+        self.data.send(OwnedRecord {
+            msg: buffers.msg,
+            location: *record.location(),
+            tag: buffers.tag,
+            level: record.level(),
+            kv: ser.0,
+            logger_values: logger_values.clone(),
+            time: SystemTime::now(),
+        })
     }
 }
 
 #[derive(Clone)]
-pub struct DrainData(Sender<OwnedRecord>);
+pub struct DrainData {
+    tx: Sender<OwnedRecord>,
+    pool: RecordPool,
+    notifier: Option<Arc<Notifier>>,
+    // Declared after `tx` so that the channel is already disconnected when the last clone's
+    // waker wakes the receiving task up
+    #[cfg(feature = "futures")]
+    waker: Arc<SenderWaker>,
+}
+
+impl DrainData {
+    /// Sets a callback to be called from a background thread when records are sent through this
+    /// data (or its clones made afterwards), so that a UI that only redraws on events can be
+    /// woken up; it's called at most once every `min_interval`, with records sent in between
+    /// delaying the next call rather than being skipped. The thread stops once the data and all
+    /// its clones are dropped.
+    pub fn with_notifier(
+        mut self,
+        callback: impl FnMut() + Send + 'static,
+        min_interval: Duration,
+    ) -> Self {
+        self.notifier = Some(Arc::new(Notifier::new(callback, min_interval)));
+        self
+    }
 
-pub struct Receiver(crossbeam_channel::Receiver<OwnedRecord>);
+    #[inline]
+    pub(crate) fn send(&self, record: OwnedRecord) -> Result<(), Error> {
+        self.tx.send(record).map_err(|_| Error::Send)?;
+        if let Some(notifier) = &self.notifier {
+            notifier.notify();
+        }
+        #[cfg(feature = "futures")]
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+pub struct Receiver {
+    pub(crate) rx: crossbeam_channel::Receiver<OwnedRecord>,
+    pub(crate) pool: RecordPool,
+    #[cfg(feature = "futures")]
+    waker: Arc<futures::task::AtomicWaker>,
+}
 
 impl Receiver {
     #[inline]
     pub fn try_iter(&self) -> impl IntoIterator<Item = OwnedRecord> + '_ {
-        self.0.try_iter()
+        self.rx.try_iter()
+    }
+
+    /// Returns the number of records waiting in the channel.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.rx.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rx.is_empty()
+    }
+
+    /// Returns the pool that processed records' buffers can be handed back to, so that the
+    /// [`Drain`]s sending to this receiver can reuse them.
+    #[inline]
+    pub fn pool(&self) -> &RecordPool {
+        &self.pool
     }
 }
 
-#[inline]
 pub fn init() -> (DrainData, Receiver) {
     let (tx, rx) = crossbeam_channel::unbounded();
-    (DrainData(tx), Receiver(rx))
+    let pool = RecordPool::new();
+    #[cfg(feature = "futures")]
+    let waker = Arc::new(futures::task::AtomicWaker::new());
+    (
+        DrainData {
+            tx,
+            pool: pool.clone(),
+            notifier: None,
+            #[cfg(feature = "futures")]
+            waker: Arc::new(SenderWaker(Arc::clone(&waker))),
+        },
+        Receiver {
+            rx,
+            pool,
+            #[cfg(feature = "futures")]
+            waker,
+        },
+    )
 }
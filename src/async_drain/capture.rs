@@ -0,0 +1,173 @@
+//! Redirecting the process's own standard output and error into the async drain, to show output
+//! that doesn't go through `slog` (such as `println!` calls or messages from native libraries).
+
+use super::{DrainData, OwnedKVList, OwnedRecord, NO_LOCATION};
+use slog::Level;
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    thread,
+    time::SystemTime,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Level to log standard output lines at, or `None` to leave it alone.
+    pub stdout_level: Option<Level>,
+    /// Level to log standard error lines at, or `None` to leave it alone.
+    pub stderr_level: Option<Level>,
+    /// Whether to still write the captured output to where it originally went.
+    pub tee: bool,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Config {
+            stdout_level: Some(Level::Info),
+            stderr_level: Some(Level::Warning),
+            tee: false,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Redirect {
+    fd: RawFd,
+    original: OwnedFd,
+}
+
+fn flush_std_stream(fd: RawFd) {
+    let _ = if fd == libc::STDOUT_FILENO {
+        io::stdout().flush()
+    } else {
+        io::stderr().flush()
+    };
+}
+
+fn read_lines(
+    mut reader: impl Read,
+    mut tee: Option<File>,
+    stream: &'static str,
+    level: Level,
+    data: DrainData,
+) {
+    let send_line = |line: &[u8]| {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            return;
+        }
+        // Output still has to be read after the receiver is dropped, or writing to the stream
+        // would eventually block
+        let _ = data.send(OwnedRecord {
+            msg: line.to_string(),
+            location: NO_LOCATION,
+            tag: String::new(),
+            level,
+            kv: OwnedKVList(Vec::new()),
+            logger_values: slog::o!("stream" => stream).into(),
+            time: SystemTime::now(),
+        });
+    };
+
+    let mut buf = vec![0; 64 * 1024];
+    let mut partial_line = Vec::new();
+    loop {
+        let mut chunk = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => &buf[..len],
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        if let Some(tee) = &mut tee {
+            let _ = tee.write_all(chunk);
+        }
+        while let Some(end) = chunk.iter().position(|&b| b == b'\n') {
+            if partial_line.is_empty() {
+                send_line(&chunk[..end]);
+            } else {
+                partial_line.extend_from_slice(&chunk[..end]);
+                send_line(&partial_line);
+                partial_line.clear();
+            }
+            chunk = &chunk[end + 1..];
+        }
+        partial_line.extend_from_slice(chunk);
+    }
+    send_line(&partial_line);
+}
+
+fn redirect(
+    fd: RawFd,
+    stream: &'static str,
+    level: Level,
+    tee: bool,
+    data: DrainData,
+) -> io::Result<Redirect> {
+    // SAFETY: The standard streams stay open for the whole lifetime of the process.
+    let original = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    let tee = if tee {
+        Some(File::from(original.try_clone()?))
+    } else {
+        None
+    };
+    let (reader, writer) = io::pipe()?;
+
+    flush_std_stream(fd);
+    // SAFETY: Both file descriptors are valid, and `fd` is only replaced, never closed.
+    if unsafe { libc::dup2(writer.as_raw_fd(), fd) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    drop(writer);
+
+    thread::spawn(move || read_lines(reader, tee, stream, level, data));
+    Ok(Redirect { fd, original })
+}
+
+/// Guard that redirects the process's standard output and error into the async drain until it's
+/// dropped.
+///
+/// While it's active, the streams are read line by line by background threads, which log each
+/// line with a `stream` logger value of `stdout` or `stderr`; the threads stop once the streams
+/// are restored and no other process (such as a child that inherited them) holds them anymore.
+pub struct Capture {
+    redirects: Vec<Redirect>,
+}
+
+impl Capture {
+    pub fn new(data: DrainData, config: &Config) -> io::Result<Self> {
+        // If redirecting the second stream fails, the first one is restored when this is dropped
+        let mut capture = Capture {
+            redirects: Vec::new(),
+        };
+        for (fd, stream, level) in [
+            (libc::STDOUT_FILENO, "stdout", config.stdout_level),
+            (libc::STDERR_FILENO, "stderr", config.stderr_level),
+        ] {
+            if let Some(level) = level {
+                capture
+                    .redirects
+                    .push(redirect(fd, stream, level, config.tee, data.clone())?);
+            }
+        }
+        Ok(capture)
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        for redirect in &self.redirects {
+            flush_std_stream(redirect.fd);
+            // SAFETY: Both file descriptors are valid.
+            unsafe {
+                libc::dup2(redirect.original.as_raw_fd(), redirect.fd);
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Spawning a child process and logging its output, one record per line.
+
+use super::{DrainData, OwnedKVList, OwnedRecord, OwnedValue, NO_LOCATION};
+use regex::Regex;
+use slog::Level;
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+pub struct Config {
+    /// Name of the group the process's records are nested in, or `None` to use the file name of
+    /// the program.
+    pub name: Option<String>,
+    /// Level of standard output lines that don't match any of `level_patterns`.
+    pub stdout_level: Level,
+    /// Level of standard error lines that don't match any of `level_patterns`.
+    pub stderr_level: Level,
+    /// Patterns to guess the level of each line from, checked in order before ANSI escape
+    /// sequences are stripped, so that colors can be matched too.
+    pub level_patterns: Vec<(Regex, Level)>,
+    /// Whether to remove ANSI escape sequences from the logged messages.
+    pub strip_ansi: bool,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        let patterns = [
+            (
+                r"\b(?:ERROR|FATAL|[Ee]rror|[Ff]atal|panicked)\b",
+                Level::Error,
+            ),
+            (r"\b(?:WARN|WARNING|[Ww]arning)\b", Level::Warning),
+            (r"\bDEBUG\b", Level::Debug),
+            (r"\bTRACE\b", Level::Trace),
+            // Red and bright red foregrounds, then yellow and bright yellow ones
+            (r"\x1b\[(?:[0-9;]*;)?(?:31|91)m", Level::Error),
+            (r"\x1b\[(?:[0-9;]*;)?(?:33|93)m", Level::Warning),
+        ];
+        Config {
+            name: None,
+            stdout_level: Level::Info,
+            stderr_level: Level::Info,
+            level_patterns: patterns
+                .into_iter()
+                .map(|(pattern, level)| (Regex::new(pattern).unwrap(), level))
+                .collect(),
+            strip_ansi: true,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Removes CSI (`ESC [ ... final`) and OSC (`ESC ] ... BEL/ST`) sequences, as well as any other
+/// two-byte escape sequences.
+fn strip_ansi(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1b' && chars.next().is_some()) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+struct Shared {
+    config: Config,
+    name: String,
+    data: DrainData,
+}
+
+impl Shared {
+    fn send(&self, msg: String, level: Level, kv: OwnedKVList) {
+        let _ = self.data.send(OwnedRecord {
+            msg,
+            location: NO_LOCATION,
+            tag: String::new(),
+            level,
+            kv,
+            logger_values: slog::o!("process" => self.name.clone()).into(),
+            time: SystemTime::now(),
+        });
+    }
+
+    fn read_lines(&self, reader: impl Read, default_level: Level) {
+        for line in BufReader::new(reader).split(b'\n') {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches('\r');
+            let level = self
+                .config
+                .level_patterns
+                .iter()
+                .find(|(pattern, _)| pattern.is_match(line))
+                .map_or(default_level, |&(_, level)| level);
+            let msg = if self.config.strip_ansi {
+                strip_ansi(line)
+            } else {
+                line.to_string()
+            };
+            if !msg.is_empty() {
+                self.send(msg, level, OwnedKVList(Vec::new()));
+            }
+        }
+    }
+}
+
+/// A child process started by [`spawn`], whose output is being logged.
+pub struct Process {
+    id: u32,
+    waiter: JoinHandle<io::Result<ExitStatus>>,
+}
+
+impl Process {
+    #[inline]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Blocks until the process has exited and all of its output has been logged.
+    pub fn wait(self) -> io::Result<ExitStatus> {
+        self.waiter
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("output thread panicked")))
+    }
+}
+
+/// Spawns `command` with its standard output and error piped into the async drain, nested in a
+/// `process` group; once both are closed, the process is waited on and its exit status logged as
+/// a final record.
+pub fn spawn(command: &mut Command, data: DrainData, config: Config) -> io::Result<Process> {
+    let name = config.name.clone().unwrap_or_else(|| {
+        let program = Path::new(command.get_program());
+        program
+            .file_name()
+            .unwrap_or(program.as_os_str())
+            .to_string_lossy()
+            .into_owned()
+    });
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let shared = Arc::new(Shared { config, name, data });
+    let stderr_thread = {
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || shared.read_lines(stderr, shared.config.stderr_level))
+    };
+    let id = child.id();
+    let waiter = thread::spawn(move || {
+        shared.read_lines(stdout, shared.config.stdout_level);
+        let _ = stderr_thread.join();
+        let result = child.wait();
+        match &result {
+            Ok(status) => shared.send(
+                "Process exited".to_string(),
+                if status.success() {
+                    Level::Info
+                } else {
+                    Level::Error
+                },
+                // Processes killed by a signal on Unix don't have an exit code
+                OwnedKVList(vec![match status.code() {
+                    Some(code) => ("code", OwnedValue::I64(code as i64)),
+                    None => ("status", OwnedValue::String(status.to_string())),
+                }]),
+            ),
+            Err(err) => shared.send(
+                format!("Couldn't wait for process: {err}"),
+                Level::Error,
+                OwnedKVList(Vec::new()),
+            ),
+        }
+        result
+    });
+
+    Ok(Process { id, waiter })
+}
@@ -0,0 +1,309 @@
+//! Reading of JSON Lines logs, as written by `slog-json` or `slog-bunyan`, back into
+//! [`OwnedRecord`]s that can be fed to [`Console::process_async`](crate::console::Console::process_async).
+
+use super::{
+    timestamp::parse_rfc3339, untrusted_pair, OwnedKVList, OwnedRecord, OwnedValue, NO_LOCATION,
+};
+use crate::intern::intern;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde_json::Value;
+use slog::{Key, Level};
+use std::{
+    fmt, io,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+/// Numeric timestamps at least this large are taken to be in milliseconds rather than seconds
+/// (as seconds, they'd be thousands of years in the future).
+const MIN_EPOCH_MILLIS: f64 = 1e11;
+
+/// The fields `slog-bunyan` adds to every record, which are turned into logger context by
+/// default.
+pub const BUNYAN_CONTEXT_KEYS: [&str; 3] = ["name", "hostname", "pid"];
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    InvalidLevel(Value),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Json(err) => write!(f, "{err}"),
+            Error::InvalidLevel(level) => write!(f, "invalid level: {level}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+/// The fields of a JSON object, in the order they appear in.
+struct Fields(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = Fields;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fields, A::Error> {
+                let mut fields = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(field) = map.next_entry()? {
+                    fields.push(field);
+                }
+                Ok(Fields(fields))
+            }
+        }
+
+        deserializer.deserialize_map(FieldsVisitor)
+    }
+}
+
+fn parse_level(value: &Value) -> Option<Level> {
+    match value {
+        Value::String(name) => Level::from_str(name).ok(),
+        Value::Number(number) => {
+            let level = number.as_u64()?;
+            Some(match level {
+                60.. => Level::Critical,
+                50.. => Level::Error,
+                40.. => Level::Warning,
+                30.. => Level::Info,
+                20.. => Level::Debug,
+                _ => Level::Trace,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Parses an RFC 3339 timestamp, or a number of seconds or milliseconds since the Unix epoch.
+fn parse_time(value: &Value) -> Option<SystemTime> {
+    match value {
+        Value::String(value) => parse_rfc3339(value),
+        Value::Number(number) => {
+            let mut secs = number.as_f64()?;
+            if secs >= MIN_EPOCH_MILLIS {
+                secs /= 1000.0;
+            }
+            SystemTime::UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(secs).ok()?)
+        }
+        _ => None,
+    }
+}
+
+fn to_owned_value(value: Value) -> OwnedValue {
+    match value {
+        Value::Null => OwnedValue::None,
+        Value::Bool(value) => OwnedValue::Bool(value),
+        Value::Number(number) => {
+            if let Some(value) = number.as_u64() {
+                OwnedValue::U64(value)
+            } else if let Some(value) = number.as_i64() {
+                OwnedValue::I64(value)
+            } else {
+                OwnedValue::F64(number.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        Value::String(value) => OwnedValue::String(value),
+        value => OwnedValue::String(value.to_string()),
+    }
+}
+
+/// Parses a single JSON object into a record.
+///
+/// `level` may either be a level name (like `slog-json`'s `"INFO"`) or a `bunyan` level number,
+/// `ts` (or `bunyan`'s `time`) becomes the record's time if it's an RFC 3339 timestamp or a number
+/// of seconds or milliseconds since the Unix epoch (the time it's parsed at is used otherwise) and
+/// is also kept as its first key-value pair, and the fields named in
+/// `context_keys` become logger values, outermost first; all other fields are kept as record
+/// key-value pairs, in order.
+pub fn parse_line(line: &str, context_keys: &[impl AsRef<str>]) -> Result<OwnedRecord, Error> {
+    let Fields(fields) = serde_json::from_str(line)?;
+
+    let mut level = None;
+    let mut msg = String::new();
+    let mut kv = Vec::with_capacity(fields.len());
+    let mut context: Vec<(usize, Key, OwnedValue)> = Vec::new();
+    let mut version = None;
+    let mut time = None;
+
+    for (key, value) in fields {
+        match key.as_str() {
+            "level" => level = Some(value),
+            "msg" => {
+                msg = match value {
+                    Value::String(msg) => msg,
+                    value => value.to_string(),
+                }
+            }
+            "ts" | "time" => {
+                time = parse_time(&value);
+                let key = if key == "ts" { "ts" } else { "time" };
+                kv.insert(0, (key, to_owned_value(value)));
+            }
+            "v" => version = Some(value),
+            key => {
+                // Context keys are chosen by the caller, but any other key could be unique to its
+                // record
+                if let Some(i) = context_keys.iter().position(|k| k.as_ref() == key) {
+                    context.push((i, intern(key), to_owned_value(value)));
+                } else {
+                    kv.push(untrusted_pair(key, to_owned_value(value)));
+                }
+            }
+        }
+    }
+
+    let level = level.unwrap_or(Value::Null);
+    let Some(level_value) = parse_level(&level) else {
+        return Err(Error::InvalidLevel(level));
+    };
+    // `v` is `bunyan`'s format version, which only means something alongside a numeric level
+    if let Some(version) = version {
+        if !level.is_number() {
+            kv.push(("v", to_owned_value(version)));
+        }
+    }
+
+    context.sort_by_key(|&(i, ..)| std::cmp::Reverse(i));
+    let logger_values = slog::OwnedKV(OwnedKVList(
+        context
+            .into_iter()
+            .map(|(_, key, value)| (key, value))
+            .collect(),
+    ));
+
+    Ok(OwnedRecord {
+        msg,
+        location: NO_LOCATION,
+        tag: String::new(),
+        level: level_value,
+        kv: OwnedKVList(kv),
+        logger_values: logger_values.into(),
+        time: time.unwrap_or_else(SystemTime::now),
+    })
+}
+
+/// Iterator over the records in a JSON Lines log, skipping blank lines.
+pub struct Reader<R> {
+    reader: R,
+    context_keys: Vec<String>,
+    line: String,
+}
+
+impl<R: io::BufRead> Reader<R> {
+    /// Creates a reader that turns [`BUNYAN_CONTEXT_KEYS`] into logger values.
+    pub fn new(reader: R) -> Self {
+        Self::with_context_keys(
+            reader,
+            BUNYAN_CONTEXT_KEYS
+                .iter()
+                .map(|key| key.to_string())
+                .collect(),
+        )
+    }
+
+    /// Creates a reader that turns the given fields into logger values, outermost first.
+    pub fn with_context_keys(reader: R, context_keys: Vec<String>) -> Self {
+        Reader {
+            reader,
+            context_keys,
+            line: String::new(),
+        }
+    }
+}
+
+impl<R: io::BufRead> Iterator for Reader<R> {
+    type Item = Result<OwnedRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+            let line = self.line.trim();
+            if !line.is_empty() {
+                return Some(parse_line(line, &self.context_keys));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(line: &str) -> SystemTime {
+        let line = format!(r#"{{"level":"INFO",{}"#, &line[1..]);
+        parse_line(&line, &[] as &[&str]).unwrap().time
+    }
+
+    #[test]
+    fn timestamps() {
+        let secs = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(
+            time(r#"{"ts":"2001-09-09T01:46:40Z"}"#),
+            secs(1_000_000_000)
+        );
+        assert_eq!(
+            time(r#"{"time":"2001-09-09T03:46:40+02:00"}"#),
+            secs(1_000_000_000)
+        );
+        assert_eq!(time(r#"{"ts":1000000000}"#), secs(1_000_000_000));
+        assert_eq!(time(r#"{"ts":1000000000000}"#), secs(1_000_000_000));
+        assert_eq!(
+            time(r#"{"ts":1000000000.25}"#),
+            secs(1_000_000_000) + Duration::from_millis(250)
+        );
+
+        let before = SystemTime::now();
+        for line in [
+            r#"{"v":0}"#,
+            r#"{"ts":"yesterday"}"#,
+            r#"{"ts":-1}"#,
+            r#"{"ts":1e300}"#,
+        ] {
+            assert!(time(line) >= before, "{line}");
+        }
+    }
+
+    #[test]
+    fn fields() {
+        let record = parse_line(
+            r#"{"msg":"hi","level":40,"ts":0,"req":7,"a":true,"v":1}"#,
+            &["req"],
+        )
+        .unwrap();
+        assert_eq!(record.msg, "hi");
+        assert_eq!(record.level, Level::Warning);
+        assert_eq!(
+            record.kv.0.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+            ["ts", "a"]
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! Forwarding of records logged through the `log` facade to the async drain.
+
+use super::{DrainData, OwnedKVList, OwnedRecord, OwnedValue};
+use crate::intern::intern;
+use slog::{Level, RecordLocation};
+use std::time::SystemTime;
+
+fn to_slog_level(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::Error,
+        log::Level::Warn => Level::Warning,
+        log::Level::Info => Level::Info,
+        log::Level::Debug => Level::Debug,
+        log::Level::Trace => Level::Trace,
+    }
+}
+
+/// [`log::Log`] implementation that sends records to the async drain, with their target as a
+/// `target` logger value.
+pub struct LogBridge {
+    data: DrainData,
+}
+
+impl LogBridge {
+    #[inline]
+    pub fn new(data: DrainData) -> Self {
+        LogBridge { data }
+    }
+}
+
+impl log::Log for LogBridge {
+    #[inline]
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let location = RecordLocation {
+            file: record
+                .file_static()
+                .unwrap_or_else(|| intern(record.file().unwrap_or_default())),
+            line: record.line().unwrap_or(0),
+            column: 0,
+            function: "",
+            module: record
+                .module_path_static()
+                .unwrap_or_else(|| intern(record.module_path().unwrap_or_default())),
+        };
+        let _ = self.data.send(OwnedRecord {
+            msg: record.args().to_string(),
+            location,
+            tag: String::new(),
+            level: to_slog_level(record.level()),
+            kv: OwnedKVList(Vec::new()),
+            logger_values: slog::OwnedKV(OwnedKVList(vec![(
+                "target",
+                OwnedValue::String(record.target().to_string()),
+            )]))
+            .into(),
+            time: SystemTime::now(),
+        });
+    }
+
+    #[inline]
+    fn flush(&self) {}
+}
+
+/// Sets a [`LogBridge`] sending to `data` as the global logger, and sets the maximum level of
+/// the records that will be logged through it.
+pub fn install(data: DrainData, max_level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(LogBridge::new(data)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+struct Shared {
+    pending: AtomicBool,
+    closed: Mutex<bool>,
+    cond: Condvar,
+}
+
+/// Calls a callback from a background thread whenever it's notified, at most once every
+/// `min_interval`; notifications that come in too soon are delayed rather than dropped, and any
+/// number of them made before the callback runs are merged into one call.
+pub(super) struct Notifier {
+    shared: Arc<Shared>,
+}
+
+impl Notifier {
+    pub fn new(mut callback: impl FnMut() + Send + 'static, min_interval: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            pending: AtomicBool::new(false),
+            closed: Mutex::new(false),
+            cond: Condvar::new(),
+        });
+        let thread_shared = Arc::clone(&shared);
+        thread::spawn(move || {
+            let shared = thread_shared;
+            loop {
+                {
+                    let mut closed = shared.closed.lock().unwrap_or_else(PoisonError::into_inner);
+                    while !shared.pending.load(Ordering::Acquire) && !*closed {
+                        closed = shared
+                            .cond
+                            .wait(closed)
+                            .unwrap_or_else(PoisonError::into_inner);
+                    }
+                    if *closed {
+                        return;
+                    }
+                }
+                shared.pending.store(false, Ordering::Release);
+                callback();
+                thread::sleep(min_interval);
+            }
+        });
+        Notifier { shared }
+    }
+
+    #[inline]
+    pub fn notify(&self) {
+        // Only the first notification since the callback last ran needs to wake the thread up;
+        // it checks `pending` while holding the lock, so the wakeup can't be missed.
+        if !self.shared.pending.swap(true, Ordering::AcqRel) {
+            let _closed = self
+                .shared
+                .closed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.shared.cond.notify_one();
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        *self
+            .shared
+            .closed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = true;
+        self.shared.cond.notify_one();
+    }
+}
@@ -1,5 +1,10 @@
+#[cfg(any(feature = "json", feature = "socket"))]
+use crate::intern::try_intern;
 use slog::{Key, Level, Record, RecordLocation, Serializer, KV};
-use std::fmt;
+use std::{
+    fmt::{self, Write},
+    time::SystemTime,
+};
 
 pub enum OwnedValue {
     None,
@@ -35,6 +40,42 @@ impl KV for OwnedKVList {
     }
 }
 
+/// Key of the pairs read from outside the process whose own key couldn't be interned anymore,
+/// which is kept at the start of their value as `key=value` instead.
+#[cfg(any(feature = "json", feature = "socket"))]
+pub const FALLBACK_KEY: Key = crate::intern::FALLBACK_KEY;
+
+/// Returns a pair with a key read from outside the process, falling back to [`FALLBACK_KEY`] once
+/// too many distinct keys have been interned, so that peers can't grow memory without bound.
+#[cfg(any(feature = "json", feature = "socket"))]
+pub(crate) fn untrusted_pair(key: &str, value: OwnedValue) -> (Key, OwnedValue) {
+    if let Some(key) = try_intern(key) {
+        return (key, value);
+    }
+    let mut text = format!("{key}=");
+    let _ = match value {
+        OwnedValue::None => write!(text, "None"),
+        OwnedValue::Unit => write!(text, "()"),
+        OwnedValue::Bool(value) => write!(text, "{value}"),
+        OwnedValue::Char(value) => write!(text, "{value}"),
+        OwnedValue::String(value) => write!(text, "{value}"),
+        OwnedValue::U64(value) => write!(text, "{value}"),
+        OwnedValue::I64(value) => write!(text, "{value}"),
+        OwnedValue::F32(value) => write!(text, "{value}"),
+        OwnedValue::F64(value) => write!(text, "{value}"),
+    };
+    (FALLBACK_KEY, OwnedValue::String(text))
+}
+
+/// Location used for records that weren't logged through `slog`.
+pub(crate) const NO_LOCATION: RecordLocation = RecordLocation {
+    file: "",
+    line: 0,
+    column: 0,
+    function: "",
+    module: "",
+};
+
 pub struct OwnedRecord {
     pub msg: String,
     pub location: RecordLocation,
@@ -42,9 +83,20 @@ pub struct OwnedRecord {
     pub level: Level,
     pub kv: OwnedKVList,
     pub logger_values: slog::OwnedKVList,
+    /// When the record was logged, or read if the time it was logged at isn't known.
+    pub time: SystemTime,
 }
 
-pub(super) struct ToOwnedSerializer(pub OwnedKVList);
+/// Serializes key-value pairs into an [`OwnedKVList`], storing string values in the empty spare
+/// strings it's given first.
+pub(super) struct ToOwnedSerializer(pub OwnedKVList, pub Vec<String>);
+
+impl ToOwnedSerializer {
+    #[inline]
+    fn take_string(&mut self) -> String {
+        self.1.pop().unwrap_or_default()
+    }
+}
 
 impl Serializer for ToOwnedSerializer {
     #[inline]
@@ -69,7 +121,9 @@ impl Serializer for ToOwnedSerializer {
     }
     #[inline]
     fn emit_str(&mut self, key: Key, val: &str) -> slog::Result {
-        self.0 .0.push((key, OwnedValue::String(val.to_string())));
+        let mut string = self.take_string();
+        string.push_str(val);
+        self.0 .0.push((key, OwnedValue::String(string)));
         Ok(())
     }
     #[inline]
@@ -134,7 +188,9 @@ impl Serializer for ToOwnedSerializer {
     }
     #[inline]
     fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
-        self.0 .0.push((key, OwnedValue::String(fmt::format(*val))));
+        let mut string = self.take_string();
+        let _ = string.write_fmt(*val);
+        self.0 .0.push((key, OwnedValue::String(string)));
         Ok(())
     }
 }
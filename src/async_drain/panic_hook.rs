@@ -0,0 +1,55 @@
+//! Logging panics to the async drain.
+
+use super::{DrainData, OwnedKVList, OwnedRecord, OwnedValue, NO_LOCATION};
+use crate::intern::intern;
+use slog::{Level, RecordLocation};
+use std::{backtrace::Backtrace, panic, thread, time::SystemTime};
+
+/// Installs a panic hook that sends a [`Critical`](Level::Critical) record for each panic through
+/// `data`, with the panicking thread's name and a backtrace, then calls the previously installed
+/// hook.
+pub fn install(data: DrainData) {
+    let prev_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let msg = if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            "Box<dyn Any>".to_string()
+        };
+        let location = info
+            .location()
+            .map_or(NO_LOCATION, |location| RecordLocation {
+                file: intern(location.file()),
+                line: location.line(),
+                column: location.column(),
+                function: "",
+                module: "",
+            });
+        let thread = thread::current();
+
+        let _ = data.send(OwnedRecord {
+            msg,
+            location,
+            tag: String::new(),
+            level: Level::Critical,
+            kv: OwnedKVList(vec![
+                (
+                    "thread",
+                    OwnedValue::String(thread.name().unwrap_or("<unnamed>").to_string()),
+                ),
+                // Starting on a new line keeps the rest of the record on the first one
+                (
+                    "backtrace",
+                    OwnedValue::String(format!("\n{}", Backtrace::force_capture())),
+                ),
+            ]),
+            logger_values: slog::o!().into(),
+            time: SystemTime::now(),
+        });
+
+        prev_hook(info);
+    }));
+}
@@ -0,0 +1,89 @@
+use super::{OwnedKVList, OwnedRecord, OwnedValue};
+use crossbeam_channel::{Receiver, Sender};
+use slog::Key;
+
+/// Maximum number of records whose buffers are kept for reuse.
+const POOL_CAPACITY: usize = 1024;
+/// Maximum number of spare value strings kept per record.
+const MAX_SPARE_STRS: usize = 16;
+/// Strings and key-value lists with larger capacities than these are dropped rather than kept
+/// around for ordinary records.
+const MAX_BUFFER_CAPACITY: usize = 4096;
+const MAX_KV_CAPACITY: usize = 64;
+
+/// Allocations taken from a processed record, to be reused for a new one.
+#[derive(Default)]
+pub(super) struct RecordBuffers {
+    pub msg: String,
+    pub tag: String,
+    pub kv: Vec<(Key, OwnedValue)>,
+    /// Strings of `OwnedValue::String` values.
+    pub strs: Vec<String>,
+}
+
+#[inline]
+fn cleared(mut buf: String) -> String {
+    if buf.capacity() > MAX_BUFFER_CAPACITY {
+        return String::new();
+    }
+    buf.clear();
+    buf
+}
+
+impl From<OwnedRecord> for RecordBuffers {
+    fn from(record: OwnedRecord) -> Self {
+        let OwnedRecord {
+            msg,
+            tag,
+            kv: OwnedKVList(mut kv),
+            ..
+        } = record;
+        let mut strs = Vec::new();
+        for (_, value) in kv.drain(..) {
+            if let OwnedValue::String(mut value) = value {
+                if strs.len() < MAX_SPARE_STRS && value.capacity() <= MAX_BUFFER_CAPACITY {
+                    value.clear();
+                    strs.push(value);
+                }
+            }
+        }
+        if kv.capacity() > MAX_KV_CAPACITY {
+            kv = Vec::new();
+        }
+        RecordBuffers {
+            msg: cleared(msg),
+            tag: cleared(tag),
+            kv,
+            strs,
+        }
+    }
+}
+
+/// Bounded pool of the buffers of processed records, which [`Drain`](super::Drain)s reuse for new
+/// records instead of allocating them every time.
+///
+/// It's shared by the [`DrainData`](super::DrainData) and [`Receiver`](super::Receiver) returned
+/// by [`init`](super::init), and their clones.
+#[derive(Clone)]
+pub struct RecordPool {
+    tx: Sender<RecordBuffers>,
+    rx: Receiver<RecordBuffers>,
+}
+
+impl RecordPool {
+    pub(super) fn new() -> Self {
+        let (tx, rx) = crossbeam_channel::bounded(POOL_CAPACITY);
+        RecordPool { tx, rx }
+    }
+
+    /// Hands a processed record's buffers back for reuse; they're dropped if the pool is full.
+    #[inline]
+    pub fn recycle(&self, record: OwnedRecord) {
+        let _ = self.tx.try_send(record.into());
+    }
+
+    #[inline]
+    pub(super) fn take(&self) -> RecordBuffers {
+        self.rx.try_recv().unwrap_or_default()
+    }
+}
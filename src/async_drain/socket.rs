@@ -0,0 +1,441 @@
+//! Sending records to another process over a stream socket, and receiving them from any number
+//! of processes at once.
+//!
+//! Each connection starts with a frame holding the sender's source name, which the receiving end
+//! groups connections by, giving each source its own [`Receiver`] so that it can be registered
+//! with [`Console::add_source`](crate::console::Console::add_source). All frames are prefixed
+//! with their length.
+
+use super::{
+    init, untrusted_pair, DrainData, OwnedKVList, OwnedRecord, OwnedValue, Receiver,
+    ToOwnedSerializer,
+};
+use crate::{
+    binary::{
+        invalid_data, read_f32, read_f64, read_i64, read_string, read_u32, read_u64, read_u8,
+        write_f32, write_f64, write_i64, write_str, write_u32, write_u64, write_u8,
+    },
+    intern::try_intern,
+};
+use ahash::AHashMap;
+use slog::{Level, Record, RecordLocation, RecordStatic, KV};
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const MAGIC: [u8; 8] = *b"SLOGIMGS";
+const VERSION: u32 = 1;
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+/// Time to wait before accepting connections again after failing to, so that errors that last
+/// (like running out of file descriptors) don't make the listener spin.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Time to wait before accepting connections again when none are pending; listening sockets are
+/// non-blocking so that the listener can notice it was dropped.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn write_value(buf: &mut Vec<u8>, value: &OwnedValue) -> io::Result<()> {
+    match value {
+        OwnedValue::None => write_u8(buf, 0),
+        OwnedValue::Unit => write_u8(buf, 1),
+        &OwnedValue::Bool(value) => {
+            write_u8(buf, 2)?;
+            write_u8(buf, value as u8)
+        }
+        &OwnedValue::Char(value) => {
+            write_u8(buf, 3)?;
+            write_u32(buf, value as u32)
+        }
+        OwnedValue::String(value) => {
+            write_u8(buf, 4)?;
+            write_str(buf, value)
+        }
+        &OwnedValue::U64(value) => {
+            write_u8(buf, 5)?;
+            write_u64(buf, value)
+        }
+        &OwnedValue::I64(value) => {
+            write_u8(buf, 6)?;
+            write_i64(buf, value)
+        }
+        &OwnedValue::F32(value) => {
+            write_u8(buf, 7)?;
+            write_f32(buf, value)
+        }
+        &OwnedValue::F64(value) => {
+            write_u8(buf, 8)?;
+            write_f64(buf, value)
+        }
+    }
+}
+
+fn read_value(reader: &mut impl Read) -> io::Result<OwnedValue> {
+    Ok(match read_u8(reader)? {
+        0 => OwnedValue::None,
+        1 => OwnedValue::Unit,
+        2 => OwnedValue::Bool(read_u8(reader)? != 0),
+        3 => OwnedValue::Char(
+            char::from_u32(read_u32(reader)?).ok_or_else(|| invalid_data("invalid char"))?,
+        ),
+        4 => OwnedValue::String(read_string(reader)?),
+        5 => OwnedValue::U64(read_u64(reader)?),
+        6 => OwnedValue::I64(read_i64(reader)?),
+        7 => OwnedValue::F32(read_f32(reader)?),
+        8 => OwnedValue::F64(read_f64(reader)?),
+        _ => return Err(invalid_data("invalid value kind")),
+    })
+}
+
+fn write_kv_list(buf: &mut Vec<u8>, kv: &OwnedKVList) -> io::Result<()> {
+    write_u32(buf, kv.0.len() as u32)?;
+    for (key, value) in &kv.0 {
+        write_str(buf, key)?;
+        write_value(buf, value)?;
+    }
+    Ok(())
+}
+
+fn read_kv_list(reader: &mut impl Read) -> io::Result<OwnedKVList> {
+    let len = read_u32(reader)?;
+    let mut kv = Vec::new();
+    for _ in 0..len {
+        let key = read_string(reader)?;
+        kv.push(untrusted_pair(&key, read_value(reader)?));
+    }
+    Ok(OwnedKVList(kv))
+}
+
+fn write_record(buf: &mut Vec<u8>, record: &OwnedRecord) -> io::Result<()> {
+    write_u8(buf, record.level.as_usize() as u8)?;
+    let time = record
+        .time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    write_u64(buf, time.as_secs())?;
+    write_u32(buf, time.subsec_nanos())?;
+    write_str(buf, &record.msg)?;
+    write_str(buf, &record.tag)?;
+    write_str(buf, record.location.file)?;
+    write_u32(buf, record.location.line)?;
+    write_u32(buf, record.location.column)?;
+    write_str(buf, record.location.function)?;
+    write_str(buf, record.location.module)?;
+    write_kv_list(buf, &record.kv)?;
+
+    // Logger values are flattened into a single list, innermost first, which is the order
+    // they'll be serialized in on the receiving end too
+    let mut logger_values = ToOwnedSerializer(OwnedKVList(Vec::new()), Vec::new());
+    record
+        .logger_values
+        .serialize(
+            &Record::new(
+                &RecordStatic {
+                    location: &record.location,
+                    tag: &record.tag,
+                    level: record.level,
+                },
+                &format_args!("{}", record.msg),
+                slog::BorrowedKV(&record.kv),
+            ),
+            &mut logger_values,
+        )
+        .map_err(io::Error::other)?;
+    write_kv_list(buf, &logger_values.0)
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<OwnedRecord> {
+    let level = Level::from_usize(read_u8(reader)? as usize)
+        .ok_or_else(|| invalid_data("invalid level"))?;
+    let (secs, nanos) = (read_u64(reader)?, read_u32(reader)?);
+    let time = Some(nanos)
+        .filter(|&nanos| nanos < 1_000_000_000)
+        .and_then(|nanos| SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+        .ok_or_else(|| invalid_data("invalid time"))?;
+    let msg = read_string(reader)?;
+    let tag = read_string(reader)?;
+    // Locations are left out once too many strings from other processes have been interned
+    let location = RecordLocation {
+        file: try_intern(&read_string(reader)?).unwrap_or_default(),
+        line: read_u32(reader)?,
+        column: read_u32(reader)?,
+        function: try_intern(&read_string(reader)?).unwrap_or_default(),
+        module: try_intern(&read_string(reader)?).unwrap_or_default(),
+    };
+    let kv = read_kv_list(reader)?;
+    let logger_values = read_kv_list(reader)?;
+
+    Ok(OwnedRecord {
+        msg,
+        location,
+        tag,
+        level,
+        kv,
+        logger_values: slog::OwnedKV(logger_values).into(),
+        time,
+    })
+}
+
+fn write_frame(writer: &mut impl Write, buf: &[u8]) -> io::Result<()> {
+    write_u32(writer, buf.len() as u32)?;
+    writer.write_all(buf)
+}
+
+fn read_frame<'a>(reader: &mut impl Read, buf: &'a mut Vec<u8>) -> io::Result<&'a [u8]> {
+    let len = read_u32(reader)?;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data("frame too long"));
+    }
+    buf.resize(len as usize, 0);
+    reader.read_exact(buf)?;
+    Ok(buf)
+}
+
+/// Returns drain data whose records are sent over `stream` by a background thread, which stops
+/// when the stream is closed or all [`Drain`](super::Drain)s using the data are dropped.
+pub fn connect(stream: impl Write + Send + 'static, source_name: &str) -> DrainData {
+    let (data, receiver) = init();
+    let mut hello = MAGIC.to_vec();
+    write_u32(&mut hello, VERSION).unwrap();
+    write_str(&mut hello, source_name).unwrap();
+
+    thread::spawn(move || {
+        let mut writer = BufWriter::new(stream);
+        let mut buf = Vec::new();
+        write_frame(&mut writer, &hello)?;
+        while let Ok(record) = receiver.rx.recv() {
+            for record in Some(record).into_iter().chain(receiver.rx.try_iter()) {
+                buf.clear();
+                // Records that can't be sent are skipped rather than closing the stream
+                if write_record(&mut buf, &record).is_ok() && buf.len() <= MAX_FRAME_LEN as usize {
+                    write_frame(&mut writer, &buf)?;
+                }
+                receiver.pool.recycle(record);
+            }
+            writer.flush()?;
+        }
+        Ok::<_, io::Error>(())
+    });
+
+    data
+}
+
+/// A source connected to a [`Listener`], receiving the records sent by every connection made
+/// with its name.
+pub struct ListenerSource {
+    pub name: String,
+    pub receiver: Receiver,
+}
+
+/// Source drain data by source name, shared by the threads reading connections.
+type SourceData = Arc<Mutex<AHashMap<String, DrainData>>>;
+
+/// Receives the records sent by every connection made to a listening socket, which are accepted
+/// and read by background threads, with one [`ListenerSource`] per source name.
+///
+/// Dropping it closes the listening socket, and each connection once it sends another record.
+pub struct Listener {
+    sources: Vec<ListenerSource>,
+    new_sources: crossbeam_channel::Receiver<ListenerSource>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Listener {
+    /// Adds the sources whose first connection was made since the last call and returns them, so
+    /// that they can be registered with the console.
+    pub fn accept_sources(&mut self) -> &[ListenerSource] {
+        let start = self.sources.len();
+        self.sources.extend(self.new_sources.try_iter());
+        &self.sources[start..]
+    }
+
+    /// Returns the sources added by [`accept_sources`](Self::accept_sources) so far, in the order
+    /// they first connected in.
+    #[inline]
+    pub fn sources(&self) -> &[ListenerSource] {
+        &self.sources
+    }
+}
+
+fn receive(
+    stream: impl Read,
+    sources: SourceData,
+    new_sources: crossbeam_channel::Sender<ListenerSource>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut buf = Vec::new();
+
+    let mut hello = read_frame(&mut reader, &mut buf)?;
+    let mut magic = [0; MAGIC.len()];
+    hello.read_exact(&mut magic)?;
+    if magic != MAGIC || read_u32(&mut hello)? != VERSION {
+        return Err(invalid_data("unsupported protocol"));
+    }
+    let source_name = read_string(&mut hello)?;
+    let data = {
+        let mut sources = sources.lock().unwrap();
+        match sources.get(&source_name) {
+            Some(data) => data.clone(),
+            None => {
+                let (data, receiver) = init();
+                // If the listener was dropped, the receiver is too and the first record sent
+                // closes the connection
+                let _ = new_sources.send(ListenerSource {
+                    name: source_name.clone(),
+                    receiver,
+                });
+                sources.insert(source_name, data.clone());
+                data
+            }
+        }
+    };
+
+    loop {
+        let record = read_record(&mut read_frame(&mut reader, &mut buf)?)?;
+        if data.send(record).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Spawns the thread accepting connections with `accept`, which must not block.
+fn listen<S: Read + Send + 'static>(
+    mut accept: impl FnMut() -> io::Result<S> + Send + 'static,
+) -> Listener {
+    let (new_sources_tx, new_sources) = crossbeam_channel::unbounded();
+    let sources = SourceData::default();
+    let stop = Arc::new(AtomicBool::new(false));
+    thread::spawn({
+        let stop = Arc::clone(&stop);
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                match accept() {
+                    Ok(stream) => {
+                        let sources = Arc::clone(&sources);
+                        let new_sources_tx = new_sources_tx.clone();
+                        thread::spawn(move || receive(stream, sources, new_sources_tx));
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_POLL_INTERVAL)
+                    }
+                    // Errors like a connection being aborted before it's accepted only affect
+                    // that connection
+                    Err(_) => thread::sleep(ACCEPT_RETRY_DELAY),
+                }
+            }
+        }
+    });
+    Listener {
+        sources: Vec::new(),
+        new_sources,
+        stop,
+    }
+}
+
+/// Returns a listener receiving the records sent by every connection made to `listener`, which
+/// is made non-blocking.
+pub fn listen_tcp(listener: TcpListener) -> io::Result<Listener> {
+    listener.set_nonblocking(true)?;
+    Ok(listen(move || {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }))
+}
+
+/// Returns a listener receiving the records sent by every connection made to `listener`, which
+/// is made non-blocking.
+#[cfg(unix)]
+pub fn listen_unix(listener: std::os::unix::net::UnixListener) -> io::Result<Listener> {
+    listener.set_nonblocking(true)?;
+    Ok(listen(move || {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_drain::Drain;
+    use slog::{info, o, Logger};
+    use std::{net::TcpStream, time::Instant};
+
+    #[test]
+    fn connections_are_grouped_by_source_name() {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        let mut listener = listen_tcp(tcp_listener).unwrap();
+
+        for (name, msg) in [("client", "a"), ("server", "b"), ("client", "c")] {
+            let data = connect(TcpStream::connect(addr).unwrap(), name);
+            let logger = Logger::root(slog::Fuse(Drain::new(data)), o!("pid" => 1));
+            info!(logger, "{}", msg; "n" => 2);
+        }
+
+        let mut received = Vec::new();
+        let start = Instant::now();
+        while received.len() < 3 && start.elapsed() < Duration::from_secs(10) {
+            listener.accept_sources();
+            for source in listener.sources() {
+                for record in source.receiver.try_iter() {
+                    received.push((source.name.clone(), record));
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut names: Vec<_> = listener.sources().iter().map(|s| &s.name[..]).collect();
+        names.sort();
+        assert_eq!(names, ["client", "server"]);
+        received.sort_by(|(_, a), (_, b)| a.msg.cmp(&b.msg));
+        let received: Vec<_> = received
+            .iter()
+            .map(|(name, record)| (&name[..], &record.msg[..], record.kv.0.len()))
+            .collect();
+        assert_eq!(
+            received,
+            [("client", "a", 1), ("server", "b", 1), ("client", "c", 1)]
+        );
+    }
+
+    #[test]
+    fn dropping_the_listener_closes_the_socket() {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        drop(listen_tcp(tcp_listener).unwrap());
+        thread::sleep(ACCEPT_POLL_INTERVAL * 20);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn invalid_times_are_rejected() {
+        let record = OwnedRecord {
+            msg: "msg".to_string(),
+            location: crate::async_drain::NO_LOCATION,
+            tag: String::new(),
+            level: Level::Info,
+            kv: OwnedKVList(Vec::new()),
+            logger_values: o!().into(),
+            time: SystemTime::UNIX_EPOCH + Duration::new(5, 999_999_999),
+        };
+        let mut buf = Vec::new();
+        write_record(&mut buf, &record).unwrap();
+        assert_eq!(read_record(&mut &buf[..]).unwrap().time, record.time);
+        // The nanoseconds follow the level and the seconds
+        buf[9..13].copy_from_slice(&1_000_000_000u32.to_le_bytes());
+        assert!(read_record(&mut &buf[..]).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+//! Receiving records from async code.
+//!
+//! Senders wake up the last task that polled the [`Receiver`], so it should only be awaited on
+//! from one task at a time.
+
+use super::{OwnedRecord, Receiver};
+use crossbeam_channel::TryRecvError;
+use futures::{task::AtomicWaker, Stream};
+use std::{
+    future::poll_fn,
+    panic::RefUnwindSafe,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Wakes the receiving task up whenever a record is sent, and once more when the last sender is
+/// dropped so that it can see the channel was disconnected.
+pub(super) struct SenderWaker(pub Arc<AtomicWaker>);
+
+impl SenderWaker {
+    #[inline]
+    pub fn wake(&self) {
+        self.0.wake();
+    }
+}
+
+// `AtomicWaker` isn't marked as unwind safe, but a panic while waking can't leave it in an
+// inconsistent state; this keeps `Drain` usable with `slog::Logger`.
+impl RefUnwindSafe for SenderWaker {}
+
+impl Drop for SenderWaker {
+    fn drop(&mut self) {
+        self.0.wake();
+    }
+}
+
+impl Receiver {
+    fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<OwnedRecord>> {
+        match self.rx.try_recv() {
+            Ok(record) => return Poll::Ready(Some(record)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        // A record could have been sent before the waker was registered
+        self.waker.register(cx.waker());
+        match self.rx.try_recv() {
+            Ok(record) => Poll::Ready(Some(record)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// Waits for at least one record to be sent, then returns all the records available; an
+    /// empty batch is returned once all senders have been dropped and every record received.
+    pub async fn recv_batch(&self) -> Vec<OwnedRecord> {
+        match poll_fn(|cx| self.poll_recv(cx)).await {
+            Some(record) => {
+                let mut batch = vec![record];
+                batch.extend(self.rx.try_iter());
+                batch
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Stream for Receiver {
+    type Item = OwnedRecord;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<OwnedRecord>> {
+        self.poll_recv(cx)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.rx.len(), None)
+    }
+}
@@ -0,0 +1,340 @@
+//! Receiving syslog messages (in either the RFC 5424 or the RFC 3164 format) over UDP.
+//!
+//! Each message's app name and process ID are turned into `app` and `pid` logger values, in that
+//! order, and its hostname and message ID into key-value pairs. Structured data parameters become
+//! `sd` key-value pairs holding `id.name=value`, since their names are chosen by the sender.
+
+use super::{
+    init, timestamp::parse_rfc3339, OwnedKVList, OwnedRecord, OwnedValue, Receiver, NO_LOCATION,
+};
+use slog::{Key, Level};
+use std::{
+    net::UdpSocket,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Time to wait before receiving again after failing to, so that errors that last don't make the
+/// listener spin.
+const RECV_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn severity_level(severity: u8) -> Level {
+    match severity {
+        0..=2 => Level::Critical,
+        3 => Level::Error,
+        4 => Level::Warning,
+        5 | 6 => Level::Info,
+        _ => Level::Debug,
+    }
+}
+
+/// Splits off the first space-separated field of `s`.
+fn next_field(s: &str) -> (&str, &str) {
+    s.split_once(' ').unwrap_or((s, ""))
+}
+
+fn nil_to_none(field: &str) -> Option<&str> {
+    (field != "-" && !field.is_empty()).then_some(field)
+}
+
+/// Parses RFC 5424 structured data into `kv`, returning the rest of the message.
+fn parse_structured_data<'a>(mut s: &'a str, kv: &mut Vec<(Key, OwnedValue)>) -> Option<&'a str> {
+    if let Some(rest) = s.strip_prefix('-') {
+        return Some(rest);
+    }
+    while let Some(element) = s.strip_prefix('[') {
+        let (id, mut params) = element.split_at(element.find([' ', ']'])?);
+        loop {
+            params = params.trim_start_matches(' ');
+            if let Some(rest) = params.strip_prefix(']') {
+                s = rest;
+                break;
+            }
+            let (name, value) = params.split_once("=\"")?;
+            let mut unescaped = String::new();
+            let mut chars = value.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (i, '"') => break i,
+                    (_, '\\') => {
+                        let (_, c) = chars.next()?;
+                        if !matches!(c, '"' | '\\' | ']') {
+                            unescaped.push('\\');
+                        }
+                        unescaped.push(c);
+                    }
+                    (_, c) => unescaped.push(c),
+                }
+            };
+            kv.push(("sd", OwnedValue::String(format!("{id}.{name}={unescaped}"))));
+            params = &value[end + 1..];
+        }
+    }
+    Some(s)
+}
+
+struct Message {
+    time: Option<SystemTime>,
+    app_name: Option<String>,
+    proc_id: Option<String>,
+    msg: String,
+}
+
+fn parse_rfc5424(s: &str, kv: &mut Vec<(Key, OwnedValue)>) -> Option<Message> {
+    let (timestamp, s) = next_field(s);
+    let (hostname, s) = next_field(s);
+    let (app_name, s) = next_field(s);
+    let (proc_id, s) = next_field(s);
+    let (msg_id, s) = next_field(s);
+    let time = match nil_to_none(timestamp) {
+        Some(timestamp) => Some(parse_rfc3339(timestamp)?),
+        None => None,
+    };
+    if let Some(hostname) = nil_to_none(hostname) {
+        kv.push(("host", OwnedValue::String(hostname.to_string())));
+    }
+    if let Some(msg_id) = nil_to_none(msg_id) {
+        kv.push(("msgid", OwnedValue::String(msg_id.to_string())));
+    }
+    let msg = parse_structured_data(s, kv)?;
+    let msg = msg.strip_prefix(' ').unwrap_or(msg);
+    Some(Message {
+        time,
+        app_name: nil_to_none(app_name).map(str::to_string),
+        proc_id: nil_to_none(proc_id).map(str::to_string),
+        msg: msg.strip_prefix('\u{feff}').unwrap_or(msg).to_string(),
+    })
+}
+
+/// Splits a RFC 3164 tag of the form `app[pid]:` off the start of `s`, if there is one.
+fn parse_rfc3164_tag(s: &str) -> Option<(&str, Option<&str>, &str)> {
+    let (tag, rest) = next_field(s);
+    let tag = tag.strip_suffix(':')?;
+    match tag.split_once('[') {
+        Some((app_name, proc_id)) => Some((app_name, Some(proc_id.strip_suffix(']')?), rest)),
+        None => Some((tag, None, rest)),
+    }
+}
+
+fn parse_rfc3164(s: &str, kv: &mut Vec<(Key, OwnedValue)>) -> Message {
+    // The timestamp has no year or time zone, so the time the message was received at is used
+    // instead
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let s = match s.get(..16) {
+        Some(timestamp)
+            if timestamp
+                .get(..3)
+                .is_some_and(|month| MONTHS.contains(&month))
+                && timestamp.as_bytes()[3] == b' '
+                && timestamp.ends_with(' ') =>
+        {
+            &s[16..]
+        }
+        _ => s,
+    };
+
+    // The hostname is often left out by local senders, in which case the tag comes first
+    let (s, tag) = match parse_rfc3164_tag(s) {
+        Some(tag) => (s, Some(tag)),
+        None => {
+            let (hostname, rest) = next_field(s);
+            match parse_rfc3164_tag(rest) {
+                Some(tag) => {
+                    kv.push(("host", OwnedValue::String(hostname.to_string())));
+                    (rest, Some(tag))
+                }
+                None => (s, None),
+            }
+        }
+    };
+    match tag {
+        Some((app_name, proc_id, msg)) => Message {
+            time: None,
+            app_name: Some(app_name.to_string()),
+            proc_id: proc_id.map(str::to_string),
+            msg: msg.to_string(),
+        },
+        None => Message {
+            time: None,
+            app_name: None,
+            proc_id: None,
+            msg: s.to_string(),
+        },
+    }
+}
+
+/// Parses a syslog message, falling back to treating it as plain text at the
+/// [`Info`](Level::Info) level if it isn't valid.
+pub fn parse_message(data: &[u8]) -> OwnedRecord {
+    let data = String::from_utf8_lossy(data);
+    let data = data.trim_end_matches(['\n', '\r', '\0']);
+
+    let mut severity = 5;
+    let mut s = data;
+    if let Some((pri, rest)) = data.strip_prefix('<').and_then(|s| s.split_once('>')) {
+        if let Ok(pri @ 0..=191) = pri.parse::<u8>() {
+            severity = pri & 7;
+            s = rest;
+        }
+    }
+
+    let mut kv = Vec::new();
+    let message = match s.strip_prefix("1 ").and_then(|s| parse_rfc5424(s, &mut kv)) {
+        Some(message) => message,
+        None => {
+            kv.clear();
+            parse_rfc3164(s, &mut kv)
+        }
+    };
+
+    // Logger values are stored innermost first
+    let mut logger_values = Vec::new();
+    if let Some(proc_id) = message.proc_id {
+        logger_values.push(("pid", OwnedValue::String(proc_id)));
+    }
+    if let Some(app_name) = message.app_name {
+        logger_values.push(("app", OwnedValue::String(app_name)));
+    }
+
+    OwnedRecord {
+        msg: message.msg,
+        location: NO_LOCATION,
+        tag: String::new(),
+        level: severity_level(severity),
+        kv: OwnedKVList(kv),
+        logger_values: slog::OwnedKV(OwnedKVList(logger_values)).into(),
+        time: message.time.unwrap_or_else(SystemTime::now),
+    }
+}
+
+/// Returns a receiver for the messages sent to `socket`, which are read by a background thread
+/// that stops once it receives a message after the receiver has been dropped.
+pub fn listen_udp(socket: UdpSocket) -> Receiver {
+    let (data, receiver) = init();
+    thread::spawn(move || {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(len) => {
+                    if data.send(parse_message(&buf[..len])).is_err() {
+                        break;
+                    }
+                }
+                // Errors like an earlier send's ICMP port unreachable being reported here don't
+                // affect later messages
+                Err(_) => thread::sleep(RECV_RETRY_DELAY),
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{RecordStatic, Serializer, KV};
+    use std::fmt;
+
+    struct Pairs(Vec<String>);
+
+    impl Serializer for Pairs {
+        fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+            self.0.push(format!("{key}={val}"));
+            Ok(())
+        }
+    }
+
+    /// Returns the record's logger values (innermost first) and key-value pairs as `key=value`.
+    fn pairs(record: &OwnedRecord) -> (Vec<String>, Vec<String>) {
+        let (mut logger_values, mut kv) = (Pairs(Vec::new()), Pairs(Vec::new()));
+        let record_static = RecordStatic {
+            location: &NO_LOCATION,
+            tag: "",
+            level: record.level,
+        };
+        let args = format_args!("");
+        let slog_record = slog::Record::new(&record_static, &args, slog::BorrowedKV(&record.kv));
+        record
+            .logger_values
+            .serialize(&slog_record, &mut logger_values)
+            .unwrap();
+        record.kv.serialize(&slog_record, &mut kv).unwrap();
+        (logger_values.0, kv.0)
+    }
+
+    #[test]
+    fn rfc5424() {
+        let record = parse_message(
+            br#"<11>1 2003-10-11T22:14:15.003Z host app 1234 ID47 [ex@1 a="x\"y\]" b="\z"][ex@2] msg"#,
+        );
+        assert_eq!(record.level, Level::Error);
+        assert_eq!(record.msg, "msg");
+        assert_eq!(
+            record.time,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_065_910_455_003)
+        );
+        let (logger_values, kv) = pairs(&record);
+        assert_eq!(logger_values, ["pid=1234", "app=app"]);
+        assert_eq!(
+            kv,
+            [
+                "host=host",
+                "msgid=ID47",
+                r#"sd=ex@1.a=x"y]"#,
+                r"sd=ex@1.b=\z"
+            ]
+        );
+
+        let record = parse_message("<14>1 - - - - - - \u{feff}hi\n".as_bytes());
+        assert_eq!(record.level, Level::Info);
+        assert_eq!(record.msg, "hi");
+        assert_eq!(pairs(&record), (vec![], vec![]));
+    }
+
+    #[test]
+    fn invalid_rfc5424_falls_back_to_rfc3164() {
+        for data in [
+            "<11>1 yesterday host app - - - msg",
+            "<11>1 - host app - - [ex@1 a=\"unterminated] msg",
+        ] {
+            let record = parse_message(data.as_bytes());
+            assert_eq!(record.level, Level::Error);
+            assert_eq!(record.msg, data[4..], "{data}");
+        }
+    }
+
+    #[test]
+    fn rfc3164() {
+        let record = parse_message(b"<12>Oct 11 22:14:15 mymachine su[42]: 'su root' failed");
+        assert_eq!(record.level, Level::Warning);
+        assert_eq!(record.msg, "'su root' failed");
+        assert_eq!(
+            pairs(&record),
+            (
+                vec!["pid=42".to_string(), "app=su".to_string()],
+                vec!["host=mymachine".to_string()]
+            )
+        );
+
+        let record = parse_message(b"<13>Oct  1 02:03:04 cron: job done");
+        assert_eq!(record.msg, "job done");
+        assert_eq!(pairs(&record), (vec!["app=cron".to_string()], vec![]));
+
+        // Non-ASCII text where the timestamp would be
+        let record = parse_message("<13>Oct 11 22:14:1é plain text".as_bytes());
+        assert_eq!(record.msg, "Oct 11 22:14:1é plain text");
+    }
+
+    #[test]
+    fn invalid_priorities_are_kept_in_the_message() {
+        for data in ["<192>text", "<x>text", "<5text", "text"] {
+            let record = parse_message(data.as_bytes());
+            assert_eq!(record.level, Level::Info);
+            assert_eq!(record.msg, data);
+        }
+        assert_eq!(parse_message(b"<7>text").level, Level::Debug);
+        assert_eq!(parse_message(b"<0>text").level, Level::Critical);
+    }
+}
@@ -0,0 +1,333 @@
+//! Following a log file as it's being written to, similarly to `tail -F`.
+
+use super::{json, timestamp::parse_rfc3339, OwnedKVList, OwnedRecord, NO_LOCATION};
+use regex::Regex;
+use slog::Level;
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    mem::take,
+    path::PathBuf,
+    str::FromStr,
+    time::SystemTime,
+};
+
+/// Maximum number of bytes read per poll, so that opening a large file doesn't read all of it at
+/// once; the rest is read by the next polls.
+const MAX_POLL_BYTES: usize = 1024 * 1024;
+const READ_BUF_LEN: usize = 64 * 1024;
+
+pub enum Format {
+    /// JSON Lines, as read by [`json::Reader`], with the given fields turned into logger values;
+    /// lines that can't be parsed are shown as plain text.
+    Json { context_keys: Vec<String> },
+    /// Plain text, one record per line. The level is taken from the `level` capture group of
+    /// `level_regex` if it matches (and is [`Info`](Level::Info) otherwise), the time from its
+    /// `time` group if it's an RFC 3339 timestamp (and is the time the line was read at
+    /// otherwise), and the message from its `msg` group, or the whole line if there is none.
+    Text { level_regex: Regex },
+}
+
+#[cfg(unix)]
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+type FileId = Option<SystemTime>;
+
+#[cfg(not(unix))]
+fn file_id(metadata: &Metadata) -> FileId {
+    metadata.created().ok()
+}
+
+struct OpenFile {
+    file: File,
+    id: FileId,
+    pos: u64,
+}
+
+/// Record source that polls a log file for new lines, reopening it when it's rotated and starting
+/// over when it's truncated.
+pub struct Tail {
+    path: PathBuf,
+    format: Format,
+    file: Option<OpenFile>,
+    skip_existing: bool,
+    /// Whether the data read next starts in the middle of a line, which is skipped.
+    skip_partial_line: bool,
+    partial_line: Vec<u8>,
+    read_buf: Vec<u8>,
+    records: Vec<OwnedRecord>,
+}
+
+impl Tail {
+    /// Creates a source that reads the whole file, then follows it; the file doesn't need to
+    /// exist yet.
+    pub fn new(path: impl Into<PathBuf>, format: Format) -> Self {
+        Tail {
+            path: path.into(),
+            format,
+            file: None,
+            skip_existing: false,
+            skip_partial_line: false,
+            partial_line: Vec::new(),
+            read_buf: Vec::new(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Creates a source that only reads lines written after it first opens the file, skipping the
+    /// rest of the line being written then, if any.
+    pub fn new_at_end(path: impl Into<PathBuf>, format: Format) -> Self {
+        Tail {
+            skip_existing: true,
+            ..Self::new(path, format)
+        }
+    }
+
+    fn parse_line(&self, line: &[u8]) -> OwnedRecord {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+
+        let (level, time, msg) = match &self.format {
+            Format::Json { context_keys } => {
+                if let Ok(record) = json::parse_line(line, context_keys) {
+                    return record;
+                }
+                (Level::Info, None, line)
+            }
+            Format::Text { level_regex } => match level_regex.captures(line) {
+                Some(captures) => (
+                    captures
+                        .name("level")
+                        .and_then(|level| Level::from_str(level.as_str()).ok())
+                        .unwrap_or(Level::Info),
+                    captures
+                        .name("time")
+                        .and_then(|time| parse_rfc3339(time.as_str())),
+                    captures.name("msg").map_or(line, |msg| msg.as_str()),
+                ),
+                None => (Level::Info, None, line),
+            },
+        };
+
+        OwnedRecord {
+            msg: msg.to_string(),
+            location: NO_LOCATION,
+            tag: String::new(),
+            level,
+            kv: OwnedKVList(Vec::new()),
+            logger_values: slog::o!().into(),
+            time: time.unwrap_or_else(SystemTime::now),
+        }
+    }
+
+    fn push_lines(&mut self, mut data: &[u8]) {
+        if self.skip_partial_line {
+            let Some(end) = data.iter().position(|&b| b == b'\n') else {
+                return;
+            };
+            self.skip_partial_line = false;
+            data = &data[end + 1..];
+        }
+        while let Some(end) = data.iter().position(|&b| b == b'\n') {
+            let line = if self.partial_line.is_empty() {
+                &data[..end]
+            } else {
+                self.partial_line.extend_from_slice(&data[..end]);
+                &self.partial_line
+            };
+            if !line.is_empty() && line != b"\r" {
+                let record = self.parse_line(line);
+                self.records.push(record);
+            }
+            self.partial_line.clear();
+            data = &data[end + 1..];
+        }
+        self.partial_line.extend_from_slice(data);
+    }
+
+    /// Reads up to `MAX_POLL_BYTES` from the file, returning whether its end was reached.
+    fn read_new_data(&mut self) -> io::Result<bool> {
+        let file = self.file.as_mut().unwrap();
+        if file.file.metadata()?.len() < file.pos {
+            file.pos = 0;
+            self.skip_partial_line = false;
+            self.partial_line.clear();
+        }
+        file.file.seek(SeekFrom::Start(file.pos))?;
+
+        let mut read_buf = take(&mut self.read_buf);
+        read_buf.resize(READ_BUF_LEN, 0);
+        let mut read_len = 0;
+        let result = loop {
+            if read_len >= MAX_POLL_BYTES {
+                break Ok(false);
+            }
+            let file = self.file.as_mut().unwrap();
+            match file.file.read(&mut read_buf) {
+                Ok(0) => break Ok(true),
+                Ok(len) => {
+                    file.pos += len as u64;
+                    read_len += len;
+                    self.push_lines(&read_buf[..len]);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => break Err(err),
+            }
+        };
+        self.read_buf = read_buf;
+        result
+    }
+
+    /// Reads the lines that were added to the file since the last call, up to `MAX_POLL_BYTES`
+    /// of them, opening or reopening it if needed.
+    pub fn poll(&mut self) -> io::Result<()> {
+        if self.file.is_none() {
+            let mut file = File::open(&self.path)?;
+            let metadata = file.metadata()?;
+            let mut pos = 0;
+            if self.skip_existing && metadata.len() != 0 {
+                pos = metadata.len();
+                let mut last_byte = [0];
+                file.seek(SeekFrom::Start(pos - 1))?;
+                file.read_exact(&mut last_byte)?;
+                self.skip_partial_line = last_byte[0] != b'\n';
+            }
+            self.skip_existing = false;
+            self.file = Some(OpenFile {
+                id: file_id(&metadata),
+                pos,
+                file,
+            });
+        }
+
+        if !self.read_new_data()? {
+            return Ok(());
+        }
+
+        // If the path now points to a different file, the old one has been rotated and was
+        // just read to the end; its last line won't get a newline anymore.
+        let rotated = fs::metadata(&self.path)
+            .is_ok_and(|metadata| Some(file_id(&metadata)) != self.file.as_ref().map(|f| f.id));
+        if rotated {
+            self.skip_partial_line = false;
+            let partial_line = take(&mut self.partial_line);
+            if !partial_line.is_empty() {
+                let record = self.parse_line(&partial_line);
+                self.records.push(record);
+            }
+            self.file = None;
+            return self.poll();
+        }
+        Ok(())
+    }
+
+    /// Polls the file, then returns the records read from it so far; errors are ignored, as the
+    /// file will be polled again on the next call.
+    #[inline]
+    pub fn try_iter(&mut self) -> impl IntoIterator<Item = OwnedRecord> + '_ {
+        let _ = self.poll();
+        self.records.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "slog-imgui-tail-test-{name}-{}.log",
+                std::process::id()
+            ));
+            File::create(&path).unwrap();
+            TempFile(path)
+        }
+
+        fn append(&self, data: &str) {
+            let mut file = fs::OpenOptions::new().append(true).open(&self.0).unwrap();
+            file.write_all(data.as_bytes()).unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn text_format() -> Format {
+        Format::Text {
+            level_regex: Regex::new(r"^(?P<level>[A-Z]+) (?P<msg>.*)$").unwrap(),
+        }
+    }
+
+    fn msgs(tail: &mut Tail) -> Vec<String> {
+        tail.try_iter()
+            .into_iter()
+            .map(|record| record.msg)
+            .collect()
+    }
+
+    #[test]
+    fn starting_at_the_end_skips_the_partial_line() {
+        let file = TempFile::new("partial");
+        file.append("INFO old\nINFO half");
+        let mut tail = Tail::new_at_end(&file.0, text_format());
+        assert!(msgs(&mut tail).is_empty());
+        file.append(" written\nWARN new\nINFO unfinished");
+        let records: Vec<_> = tail.try_iter().into_iter().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].msg, "new");
+        assert_eq!(records[0].level, Level::Warning);
+
+        let file = TempFile::new("whole");
+        file.append("INFO old\n");
+        let mut tail = Tail::new_at_end(&file.0, text_format());
+        assert!(msgs(&mut tail).is_empty());
+        file.append("INFO new\n");
+        assert_eq!(msgs(&mut tail), ["new"]);
+    }
+
+    #[test]
+    fn large_files_are_read_over_several_polls() {
+        let file = TempFile::new("large");
+        let line = format!("INFO {}\n", "x".repeat(1000));
+        let line_count = MAX_POLL_BYTES * 3 / line.len();
+        file.append(&line.repeat(line_count));
+
+        let mut tail = Tail::new(&file.0, text_format());
+        let mut polls = Vec::new();
+        loop {
+            let count = tail.try_iter().into_iter().count();
+            if count == 0 {
+                break;
+            }
+            assert!(count <= MAX_POLL_BYTES / line.len() + READ_BUF_LEN / line.len() + 1);
+            polls.push(count);
+        }
+        assert!(polls.len() >= 3);
+        assert_eq!(polls.iter().sum::<usize>(), line_count);
+    }
+
+    #[test]
+    fn truncation_starts_over() {
+        let file = TempFile::new("truncate");
+        file.append("INFO a\nINFO b\n");
+        let mut tail = Tail::new(&file.0, text_format());
+        assert_eq!(msgs(&mut tail), ["a", "b"]);
+        File::create(&file.0).unwrap();
+        file.append("INFO c\n");
+        assert_eq!(msgs(&mut tail), ["c"]);
+    }
+}
@@ -0,0 +1,123 @@
+//! Parsing of the timestamps of records read from other programs' logs.
+
+use std::time::{Duration, SystemTime};
+
+/// Returns the number of days between the Unix epoch and the given date in the proleptic
+/// Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parses an RFC 3339 timestamp (also accepting a space between the date and the time).
+pub fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    fn num(s: &str, range: std::ops::Range<usize>) -> Option<i64> {
+        let digits = s.get(range)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
+    let bytes = s.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (num(s, 0..4)?, num(s, 5..7)?, num(s, 8..10)?);
+    let (hour, minute, second) = (num(s, 11..13)?, num(s, 14..16)?, num(s, 17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+    if let Some(frac) = rest.strip_prefix('.') {
+        let len = frac.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        for (i, digit) in frac[..len.min(9)].bytes().enumerate() {
+            nanos += (digit - b'0') as u32 * 10u32.pow(8 - i as u32);
+        }
+        rest = &frac[len..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            if rest.len() != 6 || rest.as_bytes()[3] != b':' {
+                return None;
+            }
+            sign * (num(rest, 1..3)? * 3600 + num(rest, 4..6)? * 60)
+        }
+    };
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::new(u64::try_from(secs).ok()?, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64, nanos: u32) -> Option<SystemTime> {
+        Some(SystemTime::UNIX_EPOCH + Duration::new(secs, nanos))
+    }
+
+    #[test]
+    fn valid_timestamps() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), at(0, 0));
+        assert_eq!(
+            parse_rfc3339("2000-03-01T12:34:56.5+01:30"),
+            at(951_908_696, 500_000_000)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-02-29 23:59:59.123456789123z"),
+            at(1_709_251_199, 123_456_789)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-02-29t00:00:00-00:01"),
+            at(1_709_164_860, 0)
+        );
+    }
+
+    #[test]
+    fn invalid_timestamps() {
+        for s in [
+            "",
+            "1970-01-01T00:00:00",
+            "1970-01-01T00:00:00+0100",
+            "1970-01-01X00:00:00Z",
+            "1970-13-01T00:00:00Z",
+            "1970-01-01T24:00:00Z",
+            "1970-01-01T00:00:61Z",
+            "1970-01-01T00:00:00.Z",
+            "1970-01-01T00:00:00+01:00 ",
+            "1969-12-31T23:59:59Z",
+            "197a-01-01T00:00:00Z",
+            "+970-01-01T00:00:00Z",
+        ] {
+            assert_eq!(parse_rfc3339(s), None, "{s:?}");
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! A `tracing-subscriber` layer that sends events to the async drain, nested in one group per
+//! span they were recorded in.
+
+use super::{DrainData, OwnedKVList, OwnedRecord, OwnedValue};
+use slog::{Key, Level, RecordLocation};
+use std::{
+    fmt::{self, Write},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing_core::{
+    field::{Field, Visit},
+    span, Event, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+fn to_slog_level(level: &tracing_core::Level) -> Level {
+    match *level {
+        tracing_core::Level::ERROR => Level::Error,
+        tracing_core::Level::WARN => Level::Warning,
+        tracing_core::Level::INFO => Level::Info,
+        tracing_core::Level::DEBUG => Level::Debug,
+        tracing_core::Level::TRACE => Level::Trace,
+    }
+}
+
+fn location(metadata: &'static Metadata<'static>) -> RecordLocation {
+    RecordLocation {
+        file: metadata.file().unwrap_or_default(),
+        line: metadata.line().unwrap_or(0),
+        column: 0,
+        function: "",
+        module: metadata.module_path().unwrap_or_default(),
+    }
+}
+
+/// Collects an event's fields, apart from its message.
+struct EventVisitor {
+    msg: String,
+    kv: Vec<(Key, OwnedValue)>,
+}
+
+impl Visit for EventVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.msg, "{value:?}");
+        } else {
+            self.kv
+                .push((field.name(), OwnedValue::String(format!("{value:?}"))));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.msg.push_str(value);
+        } else {
+            self.kv
+                .push((field.name(), OwnedValue::String(value.to_string())));
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.kv.push((field.name(), OwnedValue::I64(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.kv.push((field.name(), OwnedValue::U64(value)));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.kv.push((field.name(), OwnedValue::F64(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.kv.push((field.name(), OwnedValue::Bool(value)));
+    }
+}
+
+/// Formats a span's fields as `name=value` pairs separated by spaces.
+struct SpanFieldsVisitor<'a>(&'a mut String);
+
+impl SpanFieldsVisitor<'_> {
+    fn separator(&mut self) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+    }
+}
+
+impl Visit for SpanFieldsVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.separator();
+        let _ = write!(self.0, "{}={value:?}", field.name());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.separator();
+        let _ = write!(self.0, "{}={value}", field.name());
+    }
+}
+
+struct SpanData {
+    fields: String,
+    last_transition: Instant,
+    busy: Duration,
+    idle: Duration,
+}
+
+/// [`Layer`] that turns events into records whose logger values hold one entry per span they
+/// were recorded in (named after the span, with its fields as the value), so that each span is
+/// shown as a group.
+pub struct TracingLayer {
+    data: DrainData,
+    span_timing: bool,
+}
+
+impl TracingLayer {
+    #[inline]
+    pub fn new(data: DrainData) -> Self {
+        TracingLayer {
+            data,
+            span_timing: false,
+        }
+    }
+
+    /// Sets whether to log a record inside each span's group when it closes, with the time it
+    /// was entered for (`time.busy`) and not (`time.idle`).
+    #[inline]
+    pub fn with_span_timing(mut self, value: bool) -> Self {
+        self.span_timing = value;
+        self
+    }
+
+    fn logger_values<'a, S: Subscriber + for<'l> LookupSpan<'l>>(
+        scope: impl Iterator<Item = tracing_subscriber::registry::SpanRef<'a, S>>,
+    ) -> slog::OwnedKVList {
+        // Scopes go from the innermost span outwards, which is the order logger values are
+        // serialized in
+        let kv = scope
+            .map(|span| {
+                let fields = span
+                    .extensions()
+                    .get::<SpanData>()
+                    .map(|data| data.fields.clone())
+                    .unwrap_or_default();
+                (span.name(), OwnedValue::String(fields))
+            })
+            .collect();
+        slog::OwnedKV(OwnedKVList(kv)).into()
+    }
+}
+
+impl<S: Subscriber + for<'l> LookupSpan<'l>> Layer<S> for TracingLayer {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = String::new();
+        attrs.record(&mut SpanFieldsVisitor(&mut fields));
+        span.extensions_mut().insert(SpanData {
+            fields,
+            last_transition: Instant::now(),
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut SpanFieldsVisitor(&mut data.fields));
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if !self.span_timing {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            let now = Instant::now();
+            data.idle += now - data.last_transition;
+            data.last_transition = now;
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if !self.span_timing {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            let now = Instant::now();
+            data.busy += now - data.last_transition;
+            data.last_transition = now;
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if !self.span_timing {
+            return;
+        }
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some((busy, idle)) = span
+            .extensions()
+            .get::<SpanData>()
+            .map(|data| (data.busy, data.idle + data.last_transition.elapsed()))
+        else {
+            return;
+        };
+        let metadata = span.metadata();
+        let _ = self.data.send(OwnedRecord {
+            msg: "close".to_string(),
+            location: location(metadata),
+            tag: String::new(),
+            level: to_slog_level(metadata.level()),
+            kv: OwnedKVList(vec![
+                ("time.busy", OwnedValue::String(format!("{busy:?}"))),
+                ("time.idle", OwnedValue::String(format!("{idle:?}"))),
+            ]),
+            logger_values: Self::logger_values(span.scope()),
+            time: SystemTime::now(),
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = EventVisitor {
+            msg: String::new(),
+            kv: Vec::new(),
+        };
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        let logger_values = match ctx.event_scope(event) {
+            Some(scope) => Self::logger_values(scope),
+            None => slog::o!().into(),
+        };
+        let _ = self.data.send(OwnedRecord {
+            msg: visitor.msg,
+            location: location(metadata),
+            tag: String::new(),
+            level: to_slog_level(metadata.level()),
+            kv: OwnedKVList(visitor.kv),
+            logger_values,
+            time: SystemTime::now(),
+        });
+    }
+}
@@ -0,0 +1,61 @@
+//! Little-endian encoding helpers shared by the binary formats.
+
+use std::io::{self, Read, Write};
+
+macro_rules! int_fns {
+    ($($ty: ty, $write: ident, $read: ident);*$(;)?) => {
+        $(
+            #[inline]
+            pub(crate) fn $write(writer: &mut impl Write, value: $ty) -> io::Result<()> {
+                writer.write_all(&value.to_le_bytes())
+            }
+
+            #[inline]
+            pub(crate) fn $read(reader: &mut impl Read) -> io::Result<$ty> {
+                let mut bytes = [0; size_of::<$ty>()];
+                reader.read_exact(&mut bytes)?;
+                Ok(<$ty>::from_le_bytes(bytes))
+            }
+        )*
+    };
+}
+
+int_fns! {
+    u8, write_u8, read_u8;
+    u16, write_u16, read_u16;
+    u32, write_u32, read_u32;
+    u64, write_u64, read_u64;
+    f32, write_f32, read_f32;
+}
+
+#[cfg(feature = "socket")]
+int_fns! {
+    i64, write_i64, read_i64;
+    f64, write_f64, read_f64;
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn write_str(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    let len = u32::try_from(value.len()).map_err(|_| invalid_data("string too long"))?;
+    write_u32(writer, len)?;
+    writer.write_all(value.as_bytes())
+}
+
+pub(crate) fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    let len = read_u32(reader)? as u64;
+    let mut bytes = Vec::new();
+    // Read through `take` so that a corrupted length can't cause a huge allocation up front
+    if reader.take(len).read_to_end(&mut bytes)? as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("invalid UTF-8 string"))
+}
+
+/// Returns a capacity to preallocate for `len` elements read from untrusted input.
+#[inline]
+pub(crate) fn capacity_hint(len: u64) -> usize {
+    len.min(4096) as usize
+}
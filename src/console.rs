@@ -6,14 +6,37 @@ mod history;
 use history::History;
 mod filter_data;
 use filter_data::FilterData;
+mod spill;
+use spill::Spill;
+mod arena;
+mod export;
+mod group_slab;
+mod trigram;
+pub use export::{ExportFormat, ExportScope};
+mod session;
+mod sync_drain;
+pub use sync_drain::{SharedConsole, SyncDrain};
+#[cfg(feature = "async")]
+mod threaded;
+#[cfg(feature = "async")]
+#[cfg_attr(feature = "nightly", doc(cfg(feature = "async")))]
+pub use threaded::ThreadedConsole;
 
-#[cfg(any(feature = "async"))]
-use crate::async_drain::OwnedRecord;
+#[cfg(feature = "async")]
+use crate::async_drain::{OwnedRecord, Receiver, RecordPool};
 use imgui::{FontId, StyleColor, Ui};
 #[cfg(feature = "async")]
 use slog::RecordStatic;
 use slog::{Level, Record, KV};
-use std::fmt;
+#[cfg(feature = "async")]
+use std::ops::Range;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 #[derive(Clone, Copy, Debug)]
 pub struct LevelColors {
@@ -36,6 +59,18 @@ impl LevelColors {
             trace: [0.75, 0.75, 0.75, 1.],
         }
     }
+
+    #[inline]
+    pub fn get(&self, level: Level) -> [f32; 4] {
+        match level {
+            Level::Critical => self.critical,
+            Level::Error => self.error,
+            Level::Warning => self.warning,
+            Level::Info => self.info,
+            Level::Debug => self.debug,
+            Level::Trace => self.trace,
+        }
+    }
 }
 
 impl Default for LevelColors {
@@ -44,13 +79,68 @@ impl Default for LevelColors {
     }
 }
 
+/// Maximum number of records of each level to keep in the history; once a level goes over its
+/// capacity, its oldest records are removed first, regardless of the records of other levels.
+///
+/// Levels are trimmed in batches, so they can go over their capacities by up to a sixteenth in the
+/// meantime.
+#[derive(Clone, Copy, Debug)]
+pub struct LevelCapacities {
+    pub critical: usize,
+    pub error: usize,
+    pub warning: usize,
+    pub info: usize,
+    pub debug: usize,
+    pub trace: usize,
+}
+
+impl LevelCapacities {
+    pub const fn new() -> Self {
+        LevelCapacities {
+            critical: usize::MAX,
+            error: usize::MAX,
+            warning: usize::MAX,
+            info: usize::MAX,
+            debug: usize::MAX,
+            trace: usize::MAX,
+        }
+    }
+
+    fn to_array(self) -> [usize; 6] {
+        [
+            self.critical,
+            self.error,
+            self.warning,
+            self.info,
+            self.debug,
+            self.trace,
+        ]
+    }
+}
+
+impl Default for LevelCapacities {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Builder {
     pub show_options: bool,
     pub msg_filter: String,
     pub kv_filter: Vec<String>,
     pub locked_to_bottom: bool,
     pub history_capacity: usize,
+    pub history_memory_budget: Option<usize>,
+    pub level_capacities: LevelCapacities,
     pub level_colors: LevelColors,
+    /// File to write records evicted from the history to instead of dropping them; it's truncated
+    /// when the first records are written to it. Records removed because of
+    /// `level_capacities` are dropped regardless, and so are all evicted records once writing to
+    /// the file has failed.
+    pub spill_path: Option<PathBuf>,
+    /// Whether to keep a trigram index of record messages and groups, which makes refiltering
+    /// large histories much faster at the cost of a few times their memory usage.
+    pub search_index: bool,
 }
 
 impl Builder {
@@ -61,17 +151,36 @@ impl Builder {
             kv_filter: Vec::new(),
             locked_to_bottom: true,
             history_capacity: 1024 * 1024,
+            history_memory_budget: None,
+            level_capacities: LevelCapacities::new(),
             level_colors: LevelColors::new(),
+            spill_path: None,
+            search_index: false,
         }
     }
 
     pub fn build(self) -> Console {
+        let mut history = History::default();
+        if self.search_index {
+            history.enable_index();
+        }
         Console {
-            history: History::default(),
+            history,
             logger_kv_groups_ser: LoggerKVGroupsSerializer::default(),
+            msg_buf: String::new(),
+            spill: self.spill_path.map(Spill::new),
+            export_ui: ExportUi::new(),
+            sources: Vec::new(),
+            unfinished_records: 0,
+            #[cfg(feature = "async")]
+            pending_records: 0,
+            #[cfg(feature = "async")]
+            record_pool: None,
 
             locked_to_bottom: self.locked_to_bottom,
             history_capacity: self.history_capacity,
+            history_memory_budget: self.history_memory_budget,
+            level_capacities: self.level_capacities,
             level_colors: self.level_colors,
             options_vis: if self.show_options {
                 OptionsVisibility::Shown {
@@ -87,6 +196,88 @@ impl Builder {
     }
 }
 
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ExportUi {
+    path: String,
+    format: ExportFormat,
+    filtered: bool,
+    status: Option<Result<(), String>>,
+}
+
+impl ExportUi {
+    fn new() -> Self {
+        ExportUi {
+            path: "log.txt".to_string(),
+            format: ExportFormat::Text,
+            filtered: true,
+            status: None,
+        }
+    }
+
+    /// Draws the export popup, returning the scope to export with if the export button was
+    /// clicked; `status` is shown afterwards, or a progress message while `pending`.
+    fn draw_popup(&mut self, ui: &Ui, pending: bool, error_color: [f32; 4]) -> Option<ExportScope> {
+        let _popup = ui.begin_popup("##export")?;
+
+        ui.input_text("Path", &mut self.path).build();
+
+        let mut format_i = ExportFormat::ALL
+            .iter()
+            .position(|format| *format == self.format)
+            .unwrap_or(0);
+        let format_names = ExportFormat::ALL.map(ExportFormat::name);
+        if ui.combo_simple_string("Format", &mut format_i, &format_names) {
+            let prev_extension = self.format.extension();
+            self.format = ExportFormat::ALL[format_i];
+            if let Some(stem) = self
+                .path
+                .strip_suffix(prev_extension)
+                .and_then(|path| path.strip_suffix('.'))
+            {
+                self.path = format!("{stem}.{}", self.format.extension());
+            }
+        }
+
+        ui.checkbox("Only filtered records", &mut self.filtered);
+
+        let export = ui.button("Export") && !pending;
+
+        if pending {
+            ui.text_disabled("Exporting…");
+        } else {
+            match &self.status {
+                Some(Ok(())) => ui.text("Exported."),
+                Some(Err(err)) => ui.text_colored(error_color, err),
+                None => {}
+            }
+        }
+
+        export.then(|| {
+            self.status = None;
+            if self.filtered {
+                ExportScope::Filtered
+            } else {
+                ExportScope::All
+            }
+        })
+    }
+}
+
+/// Time spent refiltering the whole history (including spilled records) per frame, or when a
+/// filter is changed; refilters that take longer are continued over the next frames, showing their
+/// progress.
+const REFILTER_BUDGET: Duration = Duration::from_millis(4);
+/// Number of nodes or spilled rows refiltered between checks of the time spent.
+const REFILTER_CHUNK_NODES: usize = 4096;
+
+/// Shown after the first line of multi-line records.
+const MULTI_LINE_MARKER: &str = " [...]";
+
 enum OptionsVisibility {
     Shown {
         msg_filter_buf: String,
@@ -95,12 +286,38 @@ enum OptionsVisibility {
     Hidden,
 }
 
+/// Handle to a named record source registered with [`Console::add_source`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SourceId(u16);
+
+#[derive(Clone)]
+struct Source {
+    name: String,
+    color: [f32; 4],
+}
+
 pub struct Console {
     history: History,
     logger_kv_groups_ser: LoggerKVGroupsSerializer,
+    /// Buffer records are formatted into before their text is added to the history.
+    msg_buf: String,
+    spill: Option<Spill>,
+    export_ui: ExportUi,
+    sources: Vec<Source>,
+    /// Number of records processed through a [`SyncDrain`] since capacities were last applied.
+    unfinished_records: usize,
+    /// Number of records left in the channel by the last call to
+    /// [`process_async_budgeted`](Self::process_async_budgeted).
+    #[cfg(feature = "async")]
+    pending_records: usize,
+    /// Pool the records passed to the `process_async*` functions are handed back to.
+    #[cfg(feature = "async")]
+    record_pool: Option<RecordPool>,
 
     pub locked_to_bottom: bool,
     pub history_capacity: usize,
+    pub history_memory_budget: Option<usize>,
+    pub level_capacities: LevelCapacities,
     pub level_colors: LevelColors,
     options_vis: OptionsVisibility,
 
@@ -143,11 +360,15 @@ impl Console {
             ui.checkbox("Lock", &mut self.locked_to_bottom);
 
             let clear_button_width = ui.calc_text_size("Clear")[0] + frame_padding[0] * 2.0;
+            let export_button_width = ui.calc_text_size("Export…")[0] + frame_padding[0] * 2.0;
 
             ui.same_line();
 
-            let filter_field_width =
-                (ui.content_region_avail()[0] - clear_button_width - item_spacing[0] * 2.0) * 0.5;
+            let filter_field_width = (ui.content_region_avail()[0]
+                - clear_button_width
+                - export_button_width
+                - item_spacing[0] * 3.0)
+                * 0.5;
 
             ui.set_next_item_width(filter_field_width);
             if ui
@@ -157,6 +378,7 @@ impl Console {
             {
                 Self::update_msg_filter(
                     &mut self.history,
+                    &mut self.spill,
                     &mut self.filter_data,
                     msg_filter_buf.clone(),
                 );
@@ -171,6 +393,7 @@ impl Console {
             {
                 Self::update_kv_filter(
                     &mut self.history,
+                    &mut self.spill,
                     &mut self.filter_data,
                     if kv_filter_buf.is_empty() {
                         Vec::new()
@@ -188,21 +411,83 @@ impl Console {
                 self.clear();
             }
 
+            ui.same_line();
+            if ui.button_with_size("Export…", [export_button_width, 0.0]) {
+                self.export_ui.status = None;
+                ui.open_popup("##export");
+            }
+            self.draw_export_popup(ui);
+
+            if !self.sources.is_empty() {
+                ui.text("Sources:");
+                for (i, source) in self.sources.iter().enumerate() {
+                    ui.same_line();
+                    let mut visible =
+                        !history::source_hidden(self.filter_data.hidden_sources(), i as u16);
+                    let _color = ui.push_style_color(StyleColor::Text, source.color);
+                    if ui.checkbox(format!("{}##source_{i}", source.name), &mut visible) {
+                        Self::update_source_visibility(
+                            &mut self.history,
+                            &mut self.spill,
+                            &mut self.filter_data,
+                            i as u16,
+                            visible,
+                        );
+                    }
+                }
+            }
+
+            if let Some(progress) = self.refilter_progress() {
+                ui.text_disabled(format!("Filtering… {:.0}%", progress * 100.0));
+            }
+            if let Some(err) = self.spill.as_ref().and_then(Spill::write_error) {
+                ui.text_colored(
+                    self.level_colors.error,
+                    format!("Couldn't spill records, dropping them instead: {err}"),
+                );
+            }
+            #[cfg(feature = "async")]
+            if self.pending_records != 0 {
+                ui.text_disabled(format!("Catching up… {} pending", self.pending_records));
+            }
+
             ui.dummy([0.0, 6.0]);
             ui.separator();
             ui.dummy([0.0, 6.0]);
         }
     }
 
+    fn draw_export_popup(&mut self, ui: &Ui) {
+        let Some(scope) = self
+            .export_ui
+            .draw_popup(ui, false, self.level_colors.error)
+        else {
+            return;
+        };
+        self.export_ui.status = Some(
+            File::create(&self.export_ui.path)
+                .and_then(|file| self.export(BufWriter::new(file), self.export_ui.format, scope))
+                .map_err(|err| err.to_string()),
+        );
+    }
+
     pub fn draw(&mut self, ui: &Ui) {
-        let history = if self.filter_data.filtering_enabled() {
+        Self::continue_refilter(&mut self.history, &mut self.spill, &self.filter_data);
+
+        let filtering_enabled = self.filter_data.filtering_enabled();
+        let history = if filtering_enabled {
             &self.history.filtered
         } else {
             &self.history.all
         };
+        let spilled_len = self
+            .spill
+            .as_ref()
+            .map_or(0, |spill| spill.len(filtering_enabled));
+        let len = spilled_len + history.len();
 
         let line_height = ui.frame_height_with_spacing() as f64;
-        let history_height = history.len() as f64 * line_height;
+        let history_height = len as f64 * line_height;
         let window_height = ui.window_size()[1] as f64;
 
         if self.locked_to_bottom {
@@ -218,32 +503,76 @@ impl Console {
             0.0
         };
 
-        let start_i = (((top_y + y_offset) / line_height).floor() as usize).min(history.len());
-        let end_i = (((bot_y + y_offset) / line_height).ceil() as usize).min(history.len());
+        let start_i = (((top_y + y_offset) / line_height).floor() as usize).min(len);
+        let end_i = (((bot_y + y_offset) / line_height).ceil() as usize).min(len);
 
-        let (indent_spacing, frame_padding) = unsafe {
+        let (indent_spacing, frame_padding, item_spacing) = unsafe {
             let style = ui.style();
-            (style.indent_spacing, style.frame_padding)
+            (
+                style.indent_spacing,
+                style.frame_padding,
+                style.item_spacing,
+            )
         };
 
+        let source_column_width = self
+            .sources
+            .iter()
+            .map(|source| ui.calc_text_size(&source.name)[0] + item_spacing[0])
+            .fold(0.0, f32::max);
+
         ui.dummy([0.0, (start_i as f64 * line_height - y_offset) as f32]);
 
+        let line_y = |i: usize| (i as f64 * line_height - y_offset) as f32;
+
+        if start_i < spilled_len {
+            let spill = self.spill.as_mut().unwrap();
+            match spill.rows(filtering_enabled, start_i..end_i.min(spilled_len)) {
+                Ok(rows) => {
+                    for (i, row) in (start_i..).zip(rows) {
+                        Self::draw_line(
+                            ui,
+                            i,
+                            line_y(i),
+                            source_column_width + row.indent as f32 * indent_spacing,
+                            &row.text,
+                            row.level.map_or_else(
+                                || ui.style_color(StyleColor::Text),
+                                |level| self.level_colors.get(level),
+                            ),
+                            frame_padding,
+                        );
+                        if let Some(source) = self.sources.get(row.source as usize) {
+                            Self::draw_source(ui, line_y(i), source, frame_padding);
+                        }
+                    }
+                }
+                Err(err) => Self::draw_line(
+                    ui,
+                    start_i,
+                    line_y(start_i),
+                    0.0,
+                    &format!("Couldn't read spilled records: {err}"),
+                    self.level_colors.error,
+                    frame_padding,
+                ),
+            }
+        }
+
         for (i, node) in history
             .iter()
             .enumerate()
-            .skip(start_i)
-            .take(end_i - start_i)
+            .skip(start_i.saturating_sub(spilled_len))
+            .take(end_i.saturating_sub(start_i.max(spilled_len)))
         {
-            let cursor_pos = [0.0, (i as f64 * line_height - y_offset) as f32];
-            ui.set_cursor_pos(cursor_pos);
+            let i = i + spilled_len;
 
-            let indent = node.indent as f32 * indent_spacing;
-
-            let (text, text_color) = unsafe {
+            let (text, text_color, source) = unsafe {
                 match node.kind {
                     history::NodeKind::Group => (
-                        &self.history.groups.get(&node.id).unwrap_unchecked().kv_str,
+                        &*self.history.groups[node.id].kv_str,
                         ui.style_color(StyleColor::Text),
+                        history::NO_SOURCE,
                     ),
 
                     history::NodeKind::Leaf => {
@@ -252,69 +581,107 @@ impl Console {
                             .leaves
                             .get_unchecked((node.id - self.history.cur_leaf_base_id) as usize);
                         (
-                            &leaf.msg,
-                            match leaf.level {
-                                Level::Critical => self.level_colors.critical,
-                                Level::Error => self.level_colors.error,
-                                Level::Warning => self.level_colors.warning,
-                                Level::Info => self.level_colors.info,
-                                Level::Debug => self.level_colors.debug,
-                                Level::Trace => self.level_colors.trace,
-                            },
+                            self.history.leaf_msg(leaf),
+                            self.level_colors.get(leaf.level),
+                            leaf.source,
                         )
                     }
                 }
             };
 
-            let _id = ui.push_id_usize(i);
+            Self::draw_line(
+                ui,
+                i,
+                line_y(i),
+                source_column_width + node.indent as f32 * indent_spacing,
+                text,
+                text_color,
+                frame_padding,
+            );
+            if let Some(source) = self.sources.get(source as usize) {
+                Self::draw_source(ui, line_y(i), source, frame_padding);
+            }
+        }
 
-            let text_size = ui.calc_text_size(text);
-            let frame_size = [0, 1].map(|i| text_size[i] + frame_padding[i] * 2.0);
+        ui.set_cursor_pos([0.0, line_y(end_i)]);
+        ui.dummy([0.0, ((len - end_i) as f64 * line_height + y_offset) as f32]);
+    }
 
-            if ui.invisible_button("", [frame_size[0] + indent, frame_size[1]]) {
-                ui.set_clipboard_text(text);
-            }
+    fn draw_source(ui: &Ui, y: f32, source: &Source, frame_padding: [f32; 2]) {
+        ui.set_cursor_pos([frame_padding[0], y + frame_padding[1]]);
+        ui.text_colored(source.color, &source.name);
+    }
 
-            let color = if ui.is_item_active() {
-                Some(ui.style_color(StyleColor::ButtonActive))
-            } else if ui.is_item_hovered() {
-                Some(ui.style_color(StyleColor::ButtonHovered))
-            } else {
-                None
-            };
+    fn draw_line(
+        ui: &Ui,
+        i: usize,
+        y: f32,
+        indent: f32,
+        text: &str,
+        text_color: [f32; 4],
+        frame_padding: [f32; 2],
+    ) {
+        let cursor_pos = [0.0, y];
+        ui.set_cursor_pos(cursor_pos);
 
-            if let Some(mut color) = color {
-                color[3] *= 0.5;
-
-                let window_pos = ui.window_pos();
-                let start = [
-                    window_pos[0] - ui.scroll_x() + cursor_pos[0] + indent,
-                    window_pos[1] - ui.scroll_y() + cursor_pos[1],
-                ];
-
-                ui.get_window_draw_list()
-                    .add_rect(
-                        start,
-                        [start[0] + frame_size[0], start[1] + frame_size[1]],
-                        color,
-                    )
-                    .filled(true)
-                    .rounding(unsafe { ui.style() }.frame_rounding)
-                    .build();
-            }
+        let _id = ui.push_id_usize(i);
+
+        // Only the first line of multi-line records is shown, with the rest in a tooltip
+        let (shown_text, multi_line) = match text.split_once('\n') {
+            Some((first_line, _)) => (first_line, true),
+            None => (text, false),
+        };
+
+        let mut text_size = ui.calc_text_size(shown_text);
+        if multi_line {
+            text_size[0] += ui.calc_text_size(MULTI_LINE_MARKER)[0];
+        }
+        let frame_size = [0, 1].map(|i| text_size[i] + frame_padding[i] * 2.0);
+
+        if ui.invisible_button("", [frame_size[0] + indent, frame_size[1]]) {
+            ui.set_clipboard_text(text);
+        }
+        if multi_line && ui.is_item_hovered() {
+            ui.tooltip(|| ui.text_colored(text_color, text));
+        }
+
+        let color = if ui.is_item_active() {
+            Some(ui.style_color(StyleColor::ButtonActive))
+        } else if ui.is_item_hovered() {
+            Some(ui.style_color(StyleColor::ButtonHovered))
+        } else {
+            None
+        };
 
-            ui.set_cursor_pos([
-                cursor_pos[0] + frame_padding[0] + indent,
-                cursor_pos[1] + frame_padding[1],
-            ]);
-            ui.text_colored(text_color, text);
+        if let Some(mut color) = color {
+            color[3] *= 0.5;
+
+            let window_pos = ui.window_pos();
+            let start = [
+                window_pos[0] - ui.scroll_x() + cursor_pos[0] + indent,
+                window_pos[1] - ui.scroll_y() + cursor_pos[1],
+            ];
+
+            ui.get_window_draw_list()
+                .add_rect(
+                    start,
+                    [start[0] + frame_size[0], start[1] + frame_size[1]],
+                    color,
+                )
+                .filled(true)
+                .rounding(unsafe { ui.style() }.frame_rounding)
+                .build();
         }
 
-        ui.set_cursor_pos([0.0, (end_i as f64 * line_height - y_offset) as f32]);
-        ui.dummy([
-            0.0,
-            ((history.len() - end_i) as f64 * line_height + y_offset) as f32,
+        ui.set_cursor_pos([
+            cursor_pos[0] + frame_padding[0] + indent,
+            cursor_pos[1] + frame_padding[1],
         ]);
+        ui.text_colored(text_color, shown_text);
+        if multi_line {
+            ui.same_line_with_spacing(0.0, 0.0);
+            ui.text_disabled(MULTI_LINE_MARKER);
+        }
     }
 }
 
@@ -323,6 +690,7 @@ impl Console {
         &mut self,
         record: &Record,
         logger_values: &slog::OwnedKVList,
+        source: u16,
     ) -> Result<(), slog::Error> {
         let (indent, group_id) = {
             logger_values.serialize(record, &mut self.logger_kv_groups_ser)?;
@@ -330,67 +698,197 @@ impl Console {
         };
 
         if group_id != history::NodeId::MAX {
-            self.history.groups.get_mut(&group_id).unwrap().ref_count += 1;
+            self.history.groups[group_id].ref_count += 1;
         }
 
-        let mut msg = fmt::format(*record.msg());
+        self.msg_buf.clear();
+        let _ = fmt::write(&mut self.msg_buf, *record.msg());
+        let msg_len = self.msg_buf.len() as u32;
+        let mut kv = Vec::new();
         record.kv().serialize(
             record,
             &mut StringSerializer {
-                comma_needed: !msg.is_empty(),
-                buffer: &mut msg,
+                comma_needed: !self.msg_buf.is_empty(),
+                buffer: &mut self.msg_buf,
+                kv: &mut kv,
             },
         )?;
+        let msg = self.history.msgs.push(&self.msg_buf);
 
         let id = self.history.next_leaf_id();
         let leaf = history::Leaf {
             parent: group_id,
             filtered_parent: group_id,
             level: record.level(),
+            source,
+            removed: false,
             msg,
+            msg_len,
+            kv: kv.into_boxed_slice(),
         };
-        if self.filter_data.filtering_enabled() {
-            self.filter_data.filter_new_message()(self, indent, id, &leaf);
+        self.history.push_leaf(indent, leaf);
+        // A refilter in progress gets to the leaf on its own
+        if self.filter_data.filtering_enabled() && !self.history.refiltering() {
+            self.filter_data.filter_new_message()(self, indent, id);
         }
-        self.history.leaves.push(leaf);
-        self.history.all.push(history::Node {
-            indent,
-            kind: history::NodeKind::Leaf,
-            id,
-        });
 
         Ok(())
     }
 
-    fn finish_processing_records(&mut self) {
-        if self.history.leaves.len() > self.history_capacity {
+    fn finish_processing_records(&mut self) -> Result<(), slog::Error> {
+        self.unfinished_records = 0;
+        self.history
+            .remove_leaves_over_level_capacities(&self.level_capacities.to_array());
+
+        let mut remove_count = self.history.oldest_live_leaves_end(
             self.history
-                .remove_leaves_before(self.history.leaves.len() - self.history_capacity);
+                .live_leaf_count()
+                .saturating_sub(self.history_capacity),
+        );
+
+        if let Some(budget) = self.history_memory_budget {
+            let usage = self.history.memory_usage();
+            if usage > budget {
+                // The newest leaf is always kept, since the budget can be smaller than the
+                // history's fixed overhead
+                remove_count = remove_count.max(
+                    self.history
+                        .leaves_to_free(usage - budget)
+                        .min(self.history.leaves.len().saturating_sub(1)),
+                );
+            }
+        }
+
+        if remove_count != 0 {
+            // Leaves are evicted even if they couldn't be spilled, so that the history stays
+            // within its limits; the spill stops being written to after an error
+            let spill_result = match &mut self.spill {
+                Some(spill) => spill.write_leaves(&self.history, remove_count, &self.filter_data),
+                None => Ok(()),
+            };
+            self.history.remove_leaves_before(remove_count);
+            spill_result?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    fn process_owned_record(
+        &mut self,
+        record: &OwnedRecord,
+        source: u16,
+    ) -> Result<(), slog::Error> {
+        self.process_record(
+            &Record::new(
+                &RecordStatic {
+                    location: &record.location,
+                    tag: &record.tag,
+                    level: record.level,
+                },
+                &format_args!("{}", record.msg),
+                slog::BorrowedKV(&record.kv),
+            ),
+            &record.logger_values,
+            source,
+        )
+    }
+
+    #[cfg(feature = "async")]
+    #[inline]
+    fn recycle_record(&self, record: OwnedRecord) {
+        if let Some(pool) = &self.record_pool {
+            pool.recycle(record);
         }
     }
 
-    #[cfg(any(feature = "async"))]
+    /// Sets the pool that records passed to [`process_async`](Self::process_async),
+    /// [`process_async_from`](Self::process_async_from) and
+    /// [`process_async_merged`](Self::process_async_merged) are handed back to once processed,
+    /// usually their receiver's [`pool`](Receiver::pool), so that drains can reuse their buffers.
+    /// [`process_async_budgeted`](Self::process_async_budgeted) always uses its receiver's pool.
+    #[cfg(feature = "async")]
+    #[cfg_attr(feature = "nightly", doc(cfg(feature = "async")))]
+    #[inline]
+    pub fn set_record_pool(&mut self, pool: Option<RecordPool>) {
+        self.record_pool = pool;
+    }
+
+    #[cfg(feature = "async")]
     #[cfg_attr(feature = "nightly", doc(cfg(feature = "async")))]
     pub fn process_async(
         &mut self,
         records: impl IntoIterator<Item = OwnedRecord>,
     ) -> Result<(), slog::Error> {
         for record in records.into_iter() {
-            self.process_record(
-                &Record::new(
-                    &RecordStatic {
-                        location: &record.location,
-                        tag: &record.tag,
-                        level: record.level,
-                    },
-                    &format_args!("{}", record.msg),
-                    slog::BorrowedKV(&record.kv),
-                ),
-                &record.logger_values,
-            )?;
+            self.process_owned_record(&record, history::NO_SOURCE)?;
+            self.recycle_record(record);
         }
-        self.finish_processing_records();
-        Ok(())
+        self.finish_processing_records()
+    }
+
+    /// Processes records in the order they arrived in, marking them as coming from `source`.
+    #[cfg(feature = "async")]
+    #[cfg_attr(feature = "nightly", doc(cfg(feature = "async")))]
+    pub fn process_async_from(
+        &mut self,
+        source: SourceId,
+        records: impl IntoIterator<Item = OwnedRecord>,
+    ) -> Result<(), slog::Error> {
+        for record in records.into_iter() {
+            self.process_owned_record(&record, source.0)?;
+            self.recycle_record(record);
+        }
+        self.finish_processing_records()
+    }
+
+    /// Processes records from `receiver` until it's empty, `max_records` have been processed or
+    /// `max_duration` has passed, leaving the rest in the channel for later calls; the number of
+    /// records left is shown in the options bar.
+    #[cfg(feature = "async")]
+    #[cfg_attr(feature = "nightly", doc(cfg(feature = "async")))]
+    pub fn process_async_budgeted(
+        &mut self,
+        receiver: &Receiver,
+        max_records: usize,
+        max_duration: Duration,
+    ) -> Result<(), slog::Error> {
+        let start = Instant::now();
+        for (i, record) in receiver
+            .try_iter()
+            .into_iter()
+            .take(max_records)
+            .enumerate()
+        {
+            self.process_owned_record(&record, history::NO_SOURCE)?;
+            receiver.pool.recycle(record);
+            // Checking the time after every record would be a noticeable part of the cost of
+            // processing small ones
+            if i % 32 == 31 && start.elapsed() >= max_duration {
+                break;
+            }
+        }
+        self.pending_records = receiver.len();
+        self.finish_processing_records()
+    }
+
+    /// Processes the records from several sources at once, merged in the order they were logged
+    /// in; records from the same source are always kept in the order they arrived in.
+    #[cfg(feature = "async")]
+    #[cfg_attr(feature = "nightly", doc(cfg(feature = "async")))]
+    pub fn process_async_merged<I: IntoIterator<Item = OwnedRecord>>(
+        &mut self,
+        sources: impl IntoIterator<Item = (SourceId, I)>,
+    ) -> Result<(), slog::Error> {
+        let mut records = Vec::new();
+        for (source, source_records) in sources {
+            records.extend(source_records.into_iter().map(|record| (source, record)));
+        }
+        records.sort_by_key(|(_, record)| record.time);
+        for (source, record) in records {
+            self.process_owned_record(&record, source.0)?;
+            self.recycle_record(record);
+        }
+        self.finish_processing_records()
     }
 
     pub fn process_sync<'a>(
@@ -398,10 +896,9 @@ impl Console {
         records: impl IntoIterator<Item = (&'a Record<'a>, &'a slog::OwnedKVList)>,
     ) -> Result<(), slog::Error> {
         for (record, logger_values) in records.into_iter() {
-            self.process_record(record, logger_values)?;
+            self.process_record(record, logger_values, history::NO_SOURCE)?;
         }
-        self.finish_processing_records();
-        Ok(())
+        self.finish_processing_records()
     }
 }
 
@@ -410,68 +907,342 @@ impl Console {
     pub fn clear(&mut self) {
         self.logger_kv_groups_ser.clear();
         self.history.clear();
+        self.unfinished_records = 0;
+        #[cfg(feature = "async")]
+        {
+            self.pending_records = 0;
+        }
+        if let Some(spill) = &mut self.spill {
+            spill.clear();
+        }
+    }
+
+    /// Writes the records in the history to `writer`, starting with the ones that were spilled to
+    /// disk, which are read back from the spill file.
+    ///
+    /// Exporting only filtered records finishes the refilter in progress first, if any.
+    pub fn export(
+        &mut self,
+        writer: impl Write,
+        format: ExportFormat,
+        scope: ExportScope,
+    ) -> io::Result<()> {
+        let filtered = scope == ExportScope::Filtered && self.filter_data.filtering_enabled();
+        if filtered {
+            Self::finish_refilter(&mut self.history, &mut self.spill, &self.filter_data);
+        }
+        let nodes = if filtered {
+            &self.history.filtered
+        } else {
+            &self.history.all
+        };
+        export::export(
+            &self.history,
+            nodes,
+            self.spill.as_mut().map(|spill| (spill, filtered)),
+            writer,
+            format,
+        )
+    }
+
+    /// Writes the history held in memory, along with the current filters and sources, to `writer`
+    /// in a binary format that can be restored with [`load_session`](Self::load_session); records
+    /// that were spilled to disk aren't included.
+    pub fn save_session(&self, writer: impl Write) -> io::Result<()> {
+        session::save(
+            &self.history,
+            &session::ViewState {
+                locked_to_bottom: self.locked_to_bottom,
+                msg_filter: self.filter_data.msg_filter().to_string(),
+                kv_filter: self.filter_data.kv_filter().to_vec(),
+                sources: self
+                    .sources
+                    .iter()
+                    .enumerate()
+                    .map(|(i, source)| {
+                        (
+                            source.name.clone(),
+                            source.color,
+                            history::source_hidden(self.filter_data.hidden_sources(), i as u16),
+                        )
+                    })
+                    .collect(),
+            },
+            writer,
+        )
+    }
+
+    /// Replaces the history, filters and sources with the ones saved by
+    /// [`save_session`](Self::save_session); if the session can't be read, the console is left
+    /// untouched.
+    pub fn load_session(&mut self, reader: impl Read) -> io::Result<()> {
+        let (history, view) = session::load(reader)?;
+
+        self.clear();
+        let index_enabled = self.history.index.is_some();
+        self.history = history;
+        if index_enabled {
+            self.history.enable_index();
+        }
+        self.locked_to_bottom = view.locked_to_bottom;
+        if let OptionsVisibility::Shown {
+            msg_filter_buf,
+            kv_filter_buf,
+        } = &mut self.options_vis
+        {
+            msg_filter_buf.clone_from(&view.msg_filter);
+            *kv_filter_buf = view.kv_filter.join(", ");
+        }
+        self.filter_data = FilterData::new(view.msg_filter, view.kv_filter);
+        self.sources.clear();
+        for (i, (name, color, hidden)) in view.sources.into_iter().enumerate() {
+            self.sources.push(Source { name, color });
+            if hidden {
+                self.filter_data.set_source_hidden(i as u16, true);
+            }
+        }
+        if self.filter_data.filtering_enabled() {
+            self.history
+                .start_refilter(self.filter_data.msg_filter(), self.filter_data.kv_filter());
+            Self::continue_refilter(&mut self.history, &mut self.spill, &self.filter_data);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the history or the spilled rows are being refiltered.
+    #[cfg(feature = "async")]
+    fn refiltering(&self) -> bool {
+        self.history.refiltering() || self.spill.as_ref().is_some_and(Spill::refiltering)
+    }
+
+    /// Returns the number of rows currently shown, including spilled ones.
+    #[cfg(feature = "async")]
+    fn row_count(&self) -> usize {
+        let filtering_enabled = self.filter_data.filtering_enabled();
+        let spilled_len = self
+            .spill
+            .as_ref()
+            .map_or(0, |spill| spill.len(filtering_enabled));
+        spilled_len
+            + if filtering_enabled {
+                self.history.filtered.len()
+            } else {
+                self.history.all.len()
+            }
+    }
+
+    /// Appends copies of the shown rows in `range`, which has to be in bounds, to `rows`.
+    #[cfg(feature = "async")]
+    fn copy_rows(&mut self, range: Range<usize>, rows: &mut Vec<spill::Row>) -> io::Result<()> {
+        let filtering_enabled = self.filter_data.filtering_enabled();
+        let spilled_len = self
+            .spill
+            .as_ref()
+            .map_or(0, |spill| spill.len(filtering_enabled));
+
+        if range.start < spilled_len {
+            let spill = self.spill.as_mut().unwrap();
+            rows.extend_from_slice(
+                spill.rows(filtering_enabled, range.start..range.end.min(spilled_len))?,
+            );
+        }
+
+        let history = if filtering_enabled {
+            &self.history.filtered
+        } else {
+            &self.history.all
+        };
+        let nodes = &history
+            [range.start.saturating_sub(spilled_len)..range.end.saturating_sub(spilled_len)];
+        rows.extend(nodes.iter().map(|node| match node.kind {
+            history::NodeKind::Group => spill::Row {
+                indent: node.indent,
+                level: None,
+                source: history::NO_SOURCE,
+                text: self.history.groups[node.id].kv_str.to_string(),
+            },
+            history::NodeKind::Leaf => {
+                let leaf = &self.history.leaves[(node.id - self.history.cur_leaf_base_id) as usize];
+                spill::Row {
+                    indent: node.indent,
+                    level: Some(leaf.level),
+                    source: leaf.source,
+                    text: self.history.leaf_msg(leaf).to_string(),
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Returns the fraction of the history (including spilled records) that's been filtered so
+    /// far, if the filters were changed and the history is too large to be refiltered at once; the
+    /// rest is refiltered over the next calls to [`draw`](Self::draw).
+    pub fn refilter_progress(&self) -> Option<f32> {
+        let spill_progress = self.spill.as_ref().and_then(Spill::refilter_progress);
+        let history_progress = self.history.refilter_progress();
+        if spill_progress.is_none() && history_progress.is_none() {
+            return None;
+        }
+        let spill_len = self.spill.as_ref().map_or(0, |spill| spill.len(false));
+        let (spill_done, spill_len) = spill_progress.unwrap_or((spill_len, spill_len));
+        let (history_done, history_len) =
+            history_progress.unwrap_or((self.history.all.len(), self.history.all.len()));
+        let len = spill_len + history_len;
+        Some(if len == 0 {
+            1.0
+        } else {
+            (spill_done + history_done) as f32 / len as f32
+        })
+    }
+
+    /// Returns the approximate amount of memory used by the history, in bytes.
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.history.memory_usage()
     }
 
-    fn update_msg_filter(history: &mut History, filter_data: &mut FilterData, new: String) {
+    fn update_msg_filter(
+        history: &mut History,
+        spill: &mut Option<Spill>,
+        filter_data: &mut FilterData,
+        new: String,
+    ) {
         let filtering_was_enabled = filter_data.filtering_enabled();
         let prev = filter_data.set_msg_filter(new);
         let new = filter_data.msg_filter();
 
+        if let Some(spill) = spill {
+            spill.start_refilter(filter_data);
+        }
+
         if !filter_data.filtering_enabled() {
             history.clear_filtered();
             return;
         }
 
+        // Nodes that haven't been reached yet by a refilter in progress are filtered with the new
+        // filter anyway, so restricting the ones that were is enough
         if filtering_was_enabled && new.contains(&prev) {
             history.apply_msg_filter_restriction(new);
+            history.clean_filtered_groups();
         } else {
-            filter_data.filter_all()(history, filter_data);
+            history.start_refilter(new, filter_data.kv_filter());
         }
 
-        history.clean_filtered_groups();
+        Self::continue_refilter(history, spill, filter_data);
     }
 
-    fn update_kv_filter(history: &mut History, filter_data: &mut FilterData, new: Vec<String>) {
+    fn update_kv_filter(
+        history: &mut History,
+        spill: &mut Option<Spill>,
+        filter_data: &mut FilterData,
+        new: Vec<String>,
+    ) {
         let filtering_was_enabled = filter_data.filtering_enabled();
         let prev = filter_data.set_kv_filter(new);
         let new = filter_data.kv_filter();
 
+        if let Some(spill) = spill {
+            spill.start_refilter(filter_data);
+        }
+
         if !filter_data.filtering_enabled() {
             history.clear_filtered();
             return;
         }
 
-        if filtering_was_enabled && prev.iter().all(|elem| new.contains(elem)) {
+        if filtering_was_enabled
+            && !history.refiltering()
+            && prev.iter().all(|elem| new.contains(elem))
+        {
             history.apply_kv_filter_restriction(new);
+            history.clean_filtered_groups();
         } else {
-            filter_data.filter_all()(history, filter_data);
+            history.start_refilter(filter_data.msg_filter(), new);
         }
-
-        history.clean_filtered_groups();
+        Self::continue_refilter(history, spill, filter_data);
     }
 
-    fn filter_all<const MSG_ENABLED: bool, const KV_ENABLED: bool>(
+    fn update_source_visibility(
         history: &mut History,
+        spill: &mut Option<Spill>,
         filter_data: &mut FilterData,
+        source: u16,
+        visible: bool,
+    ) {
+        filter_data.set_source_hidden(source, !visible);
+
+        if let Some(spill) = spill {
+            spill.start_refilter(filter_data);
+        }
+
+        if !filter_data.filtering_enabled() {
+            history.clear_filtered();
+            return;
+        }
+
+        history.start_refilter(filter_data.msg_filter(), filter_data.kv_filter());
+        Self::continue_refilter(history, spill, filter_data);
+    }
+
+    /// Continues the refilters of the spilled rows and of the history in progress, if any, for up
+    /// to `REFILTER_BUDGET` in total; spilled rows are refiltered first, since they're shown first.
+    fn continue_refilter(
+        history: &mut History,
+        spill: &mut Option<Spill>,
+        filter_data: &FilterData,
     ) {
-        history.filter_all::<MSG_ENABLED, KV_ENABLED>(
+        let start = Instant::now();
+        if let Some(spill) = spill {
+            while !spill.continue_refilter(filter_data, REFILTER_CHUNK_NODES) {
+                if start.elapsed() >= REFILTER_BUDGET {
+                    return;
+                }
+            }
+        }
+        if !history.refiltering() {
+            return;
+        }
+        while !filter_data.continue_refilter()(history, filter_data, REFILTER_CHUNK_NODES) {
+            if start.elapsed() >= REFILTER_BUDGET {
+                break;
+            }
+        }
+    }
+
+    /// Finishes the refilters of the spilled rows and of the history in progress, if any, regardless
+    /// of the time it takes.
+    fn finish_refilter(history: &mut History, spill: &mut Option<Spill>, filter_data: &FilterData) {
+        if let Some(spill) = spill {
+            while !spill.continue_refilter(filter_data, REFILTER_CHUNK_NODES) {}
+        }
+        while !filter_data.continue_refilter()(history, filter_data, REFILTER_CHUNK_NODES) {}
+    }
+
+    fn continue_refilter_nodes<const MSG_ENABLED: bool, const KV_ENABLED: bool>(
+        history: &mut History,
+        filter_data: &FilterData,
+        max_nodes: usize,
+    ) -> bool {
+        history.continue_refilter::<MSG_ENABLED, KV_ENABLED>(
             filter_data.msg_filter(),
             filter_data.kv_filter(),
-        );
+            filter_data.hidden_sources(),
+            max_nodes,
+        )
     }
 
     fn filter_new_message<const MSG_ENABLED: bool, const KV_ENABLED: bool>(
         &mut self,
         indent: u16,
-        id: u64,
-        leaf: &history::Leaf,
+        id: history::NodeId,
     ) {
         self.history.filter_new_message::<MSG_ENABLED, KV_ENABLED>(
             indent,
             id,
-            leaf,
             self.filter_data.msg_filter(),
             self.filter_data.kv_filter(),
+            self.filter_data.hidden_sources(),
         );
     }
 }
@@ -505,7 +1276,12 @@ impl Console {
             msg_filter_buf.clear();
             msg_filter_buf.push_str(&value);
         }
-        Self::update_msg_filter(&mut self.history, &mut self.filter_data, value);
+        Self::update_msg_filter(
+            &mut self.history,
+            &mut self.spill,
+            &mut self.filter_data,
+            value,
+        );
     }
 
     #[inline]
@@ -525,6 +1301,445 @@ impl Console {
                 kv_filter_buf.push_str(last);
             }
         }
-        Self::update_kv_filter(&mut self.history, &mut self.filter_data, value);
+        Self::update_kv_filter(
+            &mut self.history,
+            &mut self.spill,
+            &mut self.filter_data,
+            value,
+        );
+    }
+
+    /// Registers a named source, shown in its own column next to the records processed from it
+    /// with [`process_async_from`](Self::process_async_from) or
+    /// [`process_async_merged`](Self::process_async_merged), and with a visibility toggle in the
+    /// options bar.
+    pub fn add_source(&mut self, name: impl Into<String>, color: [f32; 4]) -> SourceId {
+        assert!(
+            self.sources.len() < history::NO_SOURCE as usize,
+            "too many sources"
+        );
+        self.sources.push(Source {
+            name: name.into(),
+            color,
+        });
+        SourceId(self.sources.len() as u16 - 1)
+    }
+
+    #[inline]
+    pub fn source_visible(&self, source: SourceId) -> bool {
+        !history::source_hidden(self.filter_data.hidden_sources(), source.0)
+    }
+
+    #[inline]
+    pub fn set_source_visible(&mut self, source: SourceId, visible: bool) {
+        Self::update_source_visibility(
+            &mut self.history,
+            &mut self.spill,
+            &mut self.filter_data,
+            source.0,
+            visible,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{o, Drain, Logger};
+    use std::sync::{Arc, Mutex};
+
+    /// Drain that processes every record as a batch of its own, like `SyncDrain`.
+    struct TestDrain(Arc<Mutex<Console>>);
+
+    impl Drain for TestDrain {
+        type Ok = ();
+        type Err = slog::Error;
+
+        fn log(&self, record: &Record, values: &slog::OwnedKVList) -> slog::Result {
+            self.0
+                .lock()
+                .unwrap()
+                .process_sync(std::iter::once((record, values)))
+        }
+    }
+
+    fn logger(console: Console) -> (Arc<Mutex<Console>>, Logger) {
+        let console = Arc::new(Mutex::new(console));
+        let logger = Logger::root(TestDrain(Arc::clone(&console)).fuse(), o!());
+        (console, logger)
+    }
+
+    /// Xorshift generator, so that randomized tests are reproducible.
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// Checks that every node in `all` is nested in the groups before it.
+    fn check_all(history: &History) {
+        let mut chain: Vec<history::NodeId> = Vec::new();
+        for node in &history.all {
+            chain.truncate(node.indent as usize);
+            assert_eq!(chain.len(), node.indent as usize, "node without its groups");
+            let parent = match node.kind {
+                history::NodeKind::Group => {
+                    let group = history.groups.get(node.id).expect("freed group in `all`");
+                    assert_ne!(group.ref_count, 0);
+                    group.parent
+                }
+                history::NodeKind::Leaf => {
+                    let leaf = &history.leaves[(node.id - history.cur_leaf_base_id) as usize];
+                    assert!(!leaf.removed);
+                    leaf.parent
+                }
+            };
+            assert_eq!(
+                parent,
+                chain.last().copied().unwrap_or(history::NodeId::MAX)
+            );
+            if node.kind == history::NodeKind::Group {
+                chain.push(node.id);
+            }
+        }
+    }
+
+    /// Logs through randomly nested loggers, some of whose records are warnings.
+    fn log_nested(logger: Logger, seed: u64, count: usize) {
+        let mut random = seed;
+        let mut loggers = vec![logger];
+        for i in 0..count {
+            let depth = next_random(&mut random) as usize % loggers.len();
+            loggers.truncate(depth + 1);
+            if next_random(&mut random).is_multiple_of(4) {
+                let child = loggers[depth].new(o!("depth" => depth, "i" => i));
+                loggers.push(child);
+            }
+            if next_random(&mut random).is_multiple_of(8) {
+                slog::warn!(loggers.last().unwrap(), "record {}", i; "i" => i);
+            } else {
+                slog::info!(loggers.last().unwrap(), "record {}", i; "i" => i);
+            }
+        }
+    }
+
+    #[test]
+    fn nested_loggers_under_tiny_memory_budget() {
+        for seed in 1..=10 {
+            let mut builder = Builder::new();
+            builder.history_memory_budget = Some(20_000 + seed as usize * 5_000);
+            let (console, logger) = logger(builder.build());
+            log_nested(logger, seed, 5_000);
+
+            let console = console.lock().unwrap();
+            check_all(&console.history);
+            assert_ne!(console.history.live_leaf_count(), 0);
+        }
+    }
+
+    #[test]
+    fn filtered_export_finishes_refilter() {
+        let spill_path = std::env::temp_dir().join(format!(
+            "slog-imgui-export-test-{}.spill",
+            std::process::id()
+        ));
+        let mut builder = Builder::new();
+        builder.history_capacity = 5_000;
+        builder.spill_path = Some(spill_path.clone());
+        let (console, logger) = logger(builder.build());
+        for i in 0..20_000 {
+            slog::info!(logger, "record {}", i);
+        }
+
+        let mut console = console.lock().unwrap();
+        console.set_msg_filter("record 1".to_string());
+        let mut output = Vec::new();
+        let result = console.export(&mut output, ExportFormat::Text, ExportScope::Filtered);
+        console.clear();
+        let _ = std::fs::remove_file(spill_path);
+        result.unwrap();
+
+        let expected = (0..20_000)
+            .filter(|i| format!("record {i}").contains("record 1"))
+            .count();
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), expected);
+    }
+
+    #[test]
+    fn nested_loggers_with_empty_level() {
+        for seed in 1..=10 {
+            let mut builder = Builder::new();
+            builder.level_capacities.info = 0;
+            let (console, logger) = logger(builder.build());
+            log_nested(logger, seed, 5_000);
+
+            let console = console.lock().unwrap();
+            check_all(&console.history);
+        }
+    }
+
+    #[test]
+    fn levels_are_trimmed_past_a_sixteenth_of_their_capacity() {
+        let mut builder = Builder::new();
+        builder.level_capacities.warning = 160;
+        builder.level_capacities.info = 3_200;
+        let (console, logger) = logger(builder.build());
+        let level_len =
+            |level: Level| console.lock().unwrap().history.level_leaves[level.as_usize() - 1].len();
+
+        for i in 0..170 {
+            slog::warn!(logger, "warning {}", i);
+        }
+        // 10 warnings over the capacity, with a threshold of 160 / 16
+        assert_eq!(level_len(Level::Warning), 160);
+        for i in 0..3_399 {
+            slog::info!(logger, "info {}", i);
+        }
+        assert_eq!(level_len(Level::Info), 3_399);
+        slog::info!(logger, "info");
+        assert_eq!(level_len(Level::Info), 3_200);
+
+        let console = console.lock().unwrap();
+        check_all(&console.history);
+        assert_eq!(console.history.live_leaf_count(), 3_360);
+        // Tombstones are only compacted out once they outnumber the other leaves
+        assert!(console.history.leaves[0].removed);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn clear_resets_pending_records() {
+        let (data, receiver) = crate::async_drain::init();
+        let logger = Logger::root(crate::async_drain::Drain::new(data).fuse(), o!());
+        for i in 0..100 {
+            slog::info!(logger, "record {}", i);
+        }
+        let mut console = Builder::new().build();
+        console
+            .process_async_budgeted(&receiver, 10, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(console.pending_records, 90);
+        console.clear();
+        assert_eq!(console.pending_records, 0);
+    }
+
+    /// Returns the indents and texts of the nodes in the filtered list, or in `all` if filtering
+    /// is disabled.
+    fn shown_rows(console: &Console) -> Vec<(u16, String)> {
+        let history = &console.history;
+        let nodes = if console.filter_data.filtering_enabled() {
+            &history.filtered
+        } else {
+            &history.all
+        };
+        nodes
+            .iter()
+            .map(|node| {
+                let text = match node.kind {
+                    history::NodeKind::Group => history.groups[node.id].kv_str.to_string(),
+                    history::NodeKind::Leaf => history
+                        .leaf_msg(&history.leaves[(node.id - history.cur_leaf_base_id) as usize])
+                        .to_string(),
+                };
+                (node.indent, text)
+            })
+            .collect()
+    }
+
+    fn spill_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "slog-imgui-{name}-test-{}.spill",
+            std::process::id()
+        ))
+    }
+
+    fn export_lines(console: &mut Console, scope: ExportScope) -> Vec<String> {
+        let mut output = Vec::new();
+        console
+            .export(&mut output, ExportFormat::Text, scope)
+            .unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn memory_budget_is_respected() {
+        let budget = 512 * 1024;
+        let mut builder = Builder::new();
+        builder.history_memory_budget = Some(budget);
+        let (console, logger) = logger(builder.build());
+        for seed in 1..=4 {
+            log_nested(logger.clone(), seed, 5_000);
+            let console = console.lock().unwrap();
+            // Messages are only freed a whole arena chunk at a time
+            let usage = console.memory_usage();
+            assert!(usage <= budget + 64 * 1024, "{usage}");
+            check_all(&console.history);
+        }
+
+        let console = console.lock().unwrap();
+        // Only the oldest records are evicted
+        let live = console.history.live_leaf_count();
+        assert!(live > 100 && live < 20_000, "{live}");
+        let last = console.history.leaves.last().unwrap();
+        assert!(console.history.leaf_msg(last).starts_with("record 4999,"));
+    }
+
+    #[test]
+    fn removed_leaves_are_compacted_out() {
+        let mut builder = Builder::new();
+        builder.level_capacities.info = 100;
+        builder.search_index = true;
+        let (console, logger) = logger(builder.build());
+        for i in 0..10_000 {
+            if i % 10 == 0 {
+                slog::warn!(logger, "warning {}", i);
+            } else {
+                slog::info!(logger, "info {}", i);
+            }
+        }
+
+        let mut console = console.lock().unwrap();
+        let console = &mut *console;
+        check_all(&console.history);
+        let history = &console.history;
+        // Levels can go over their capacity by up to a sixteenth of it
+        let live_infos = history.live_leaf_count() - 1_000;
+        assert!((100..106).contains(&live_infos), "{live_infos}");
+        // Tombstones never make up more than half of the leaves for long
+        assert!(
+            history.leaves.len() < 1_100 * 2 + 200,
+            "{}",
+            history.leaves.len()
+        );
+        let infos: Vec<_> = (0..10_000).filter(|i| i % 10 != 0).collect();
+        let first_live_info = infos[infos.len() - live_infos];
+        let expected: Vec<_> = (0..10_000)
+            .filter(|i| i % 10 == 0 || *i >= first_live_info)
+            .map(|i| {
+                if i % 10 == 0 {
+                    format!("warning {i}")
+                } else {
+                    format!("info {i}")
+                }
+            })
+            .collect();
+        let rows: Vec<_> = shown_rows(console)
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+        assert_eq!(rows, expected);
+
+        // The index still finds the renumbered leaves
+        console.set_msg_filter("99".to_string());
+        Console::finish_refilter(
+            &mut console.history,
+            &mut console.spill,
+            &console.filter_data,
+        );
+        let rows: Vec<_> = shown_rows(console)
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+        let expected: Vec<_> = expected
+            .into_iter()
+            .filter(|text| text.contains("99"))
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn incremental_refilter_matches_filtering_new_records() {
+        for (msg_filter, kv_filter) in [("7", vec![]), ("", vec!["depth: 1".to_string()])] {
+            let mut builder = Builder::new();
+            builder.msg_filter = msg_filter.to_string();
+            builder.kv_filter.clone_from(&kv_filter);
+            let (filtered, filtered_logger) = logger(builder.build());
+            let (refiltered, refiltered_logger) = logger(Builder::new().build());
+            log_nested(filtered_logger.clone(), 1, 15_000);
+            log_nested(refiltered_logger.clone(), 1, 15_000);
+
+            {
+                let mut console = refiltered.lock().unwrap();
+                console.set_msg_filter(msg_filter.to_string());
+                console.set_kv_filter(kv_filter.clone());
+            }
+            // More records come in while the history is refiltered
+            let mut seed = 2;
+            while refiltered.lock().unwrap().refilter_progress().is_some() {
+                log_nested(filtered_logger.clone(), seed, 100);
+                log_nested(refiltered_logger.clone(), seed, 100);
+                seed += 1;
+                let mut console = refiltered.lock().unwrap();
+                let console = &mut *console;
+                Console::continue_refilter(
+                    &mut console.history,
+                    &mut console.spill,
+                    &console.filter_data,
+                );
+            }
+
+            let (filtered, refiltered) = (filtered.lock().unwrap(), refiltered.lock().unwrap());
+            check_all(&refiltered.history);
+            let rows = shown_rows(&refiltered);
+            assert!(!rows.is_empty());
+            assert_eq!(rows, shown_rows(&filtered), "{msg_filter:?} {kv_filter:?}");
+        }
+    }
+
+    #[test]
+    fn spilled_records_are_kept_in_order() {
+        let path = spill_path("spill");
+        let mut builder = Builder::new();
+        builder.history_capacity = 1_000;
+        builder.spill_path = Some(path.clone());
+        let (console, logger) = logger(builder.build());
+        for i in 0..5_000 {
+            slog::info!(logger, "record {}", i; "i" => i);
+        }
+
+        let mut console = console.lock().unwrap();
+        assert_eq!(console.history.live_leaf_count(), 1_000);
+        let all = export_lines(&mut console, ExportScope::All);
+        assert_eq!(all.len(), 5_000);
+        assert!(all[0].ends_with("record 0, i: 0"), "{}", all[0]);
+        assert!(
+            all[4_999].ends_with("record 4999, i: 4999"),
+            "{}",
+            all[4_999]
+        );
+
+        // Spilled rows are refiltered too
+        console.set_msg_filter("record 12".to_string());
+        let filtered = export_lines(&mut console, ExportScope::Filtered);
+        assert_eq!(filtered.len(), 111);
+        assert!(filtered[110].ends_with("record 1299, i: 1299"));
+
+        console.clear();
+        assert!(export_lines(&mut console, ExportScope::All).is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn spill_write_errors_drop_records() {
+        let mut builder = Builder::new();
+        builder.history_capacity = 100;
+        builder.spill_path = Some(spill_path("missing").join("spill"));
+        let console = Arc::new(Mutex::new(builder.build()));
+        let logger = Logger::root(TestDrain(Arc::clone(&console)).ignore_res(), o!());
+        for i in 0..1_000 {
+            slog::info!(logger, "record {}", i);
+        }
+
+        let mut console = console.lock().unwrap();
+        assert!(console.spill.as_ref().unwrap().write_error().is_some());
+        assert_eq!(console.history.live_leaf_count(), 100);
+        let all = export_lines(&mut console, ExportScope::All);
+        assert_eq!(all.len(), 100);
+        assert!(all[0].ends_with("record 900"));
     }
 }
@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Location of a string stored in a [`TextArena`].
+#[derive(Clone, Copy)]
+pub struct TextRef {
+    chunk: u32,
+    start: u32,
+    len: u32,
+}
+
+impl TextRef {
+    pub const EMPTY: Self = TextRef {
+        chunk: 0,
+        start: 0,
+        len: 0,
+    };
+
+    #[inline]
+    pub fn len(self) -> usize {
+        self.len as usize
+    }
+}
+
+/// Append-only storage for leaf messages, split into chunks that are released as a whole once the
+/// leaves using them have all been removed.
+///
+/// Chunk IDs keep increasing as chunks are added, so strings pushed later are never in an earlier
+/// chunk than ones pushed before them.
+#[derive(Default)]
+pub struct TextArena {
+    chunks: VecDeque<String>,
+    first_chunk_id: u32,
+    bytes: usize,
+}
+
+impl TextArena {
+    pub fn clear(&mut self) {
+        self.first_chunk_id = self.first_chunk_id.wrapping_add(self.chunks.len() as u32);
+        self.chunks.clear();
+        self.bytes = 0;
+    }
+
+    fn last_chunk_id(&self) -> u32 {
+        self.first_chunk_id
+            .wrapping_add(self.chunks.len().saturating_sub(1) as u32)
+    }
+
+    pub fn push(&mut self, text: &str) -> TextRef {
+        if text.is_empty() {
+            return TextRef {
+                chunk: self.last_chunk_id(),
+                ..TextRef::EMPTY
+            };
+        }
+        // Chunks are never grown, so that adding to them never copies what they already hold
+        if self
+            .chunks
+            .back()
+            .is_none_or(|chunk| chunk.capacity() - chunk.len() < text.len())
+        {
+            let chunk = String::with_capacity(text.len().max(CHUNK_SIZE));
+            self.bytes += chunk.capacity();
+            self.chunks.push_back(chunk);
+        }
+        let chunk_id = self.last_chunk_id();
+        let chunk = self.chunks.back_mut().unwrap();
+        let start = chunk.len() as u32;
+        chunk.push_str(text);
+        TextRef {
+            chunk: chunk_id,
+            start,
+            len: text.len() as u32,
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, text: TextRef) -> &str {
+        if text.len == 0 {
+            return "";
+        }
+        let chunk = &self.chunks[text.chunk.wrapping_sub(self.first_chunk_id) as usize];
+        &chunk[text.start as usize..(text.start + text.len) as usize]
+    }
+
+    /// Releases the chunks before the one `first_kept` is in, or all but the last one if it's
+    /// `None`.
+    pub fn release_before(&mut self, first_kept: Option<TextRef>) {
+        let end = first_kept.map_or(self.last_chunk_id(), |text| text.chunk);
+        while self.chunks.len() > 1 && self.first_chunk_id != end {
+            let chunk = self.chunks.pop_front().unwrap();
+            self.bytes -= chunk.capacity();
+            self.first_chunk_id = self.first_chunk_id.wrapping_add(1);
+        }
+    }
+
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_and_get() {
+        let mut arena = TextArena::default();
+        let texts: Vec<String> = (0..20_000).map(|i| format!("text {i}")).collect();
+        let big = "x".repeat(CHUNK_SIZE * 2);
+        let mut refs: Vec<_> = texts.iter().map(|text| arena.push(text)).collect();
+        let big_ref = arena.push(&big);
+        refs.push(arena.push(""));
+        assert!(arena.chunks.len() > 2);
+        for (text, text_ref) in texts.iter().zip(&refs) {
+            assert_eq!(arena.get(*text_ref), text);
+        }
+        assert_eq!(arena.get(big_ref), big);
+        assert_eq!(arena.get(*refs.last().unwrap()), "");
+        assert_eq!(arena.get(TextRef::EMPTY), "");
+        assert_eq!(
+            arena.memory_usage(),
+            arena.chunks.iter().map(String::capacity).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn release_before() {
+        let mut arena = TextArena::default();
+        let refs: Vec<_> = (0..20_000)
+            .map(|i| arena.push(&format!("text {i}")))
+            .collect();
+        let chunk_count = arena.chunks.len();
+
+        // Chunks holding the kept strings stay, along with every later chunk
+        arena.release_before(Some(refs[10_000]));
+        assert!(arena.chunks.len() < chunk_count);
+        for (i, text_ref) in refs.iter().enumerate().skip(10_000) {
+            assert_eq!(arena.get(*text_ref), format!("text {i}"));
+        }
+
+        arena.release_before(None);
+        assert_eq!(arena.chunks.len(), 1);
+        assert_eq!(arena.get(refs[19_999]), "text 19999");
+        assert_eq!(arena.memory_usage(), arena.chunks[0].capacity());
+
+        // Chunk IDs keep increasing after clearing
+        arena.clear();
+        let text_ref = arena.push("new");
+        assert!(text_ref.chunk > refs[19_999].chunk);
+        assert_eq!(arena.get(text_ref), "new");
+    }
+}
@@ -0,0 +1,284 @@
+use super::{
+    history::{History, Leaf, Node, NodeId, NodeKind, ValueKind},
+    spill::Spill,
+};
+use slog::Level;
+use std::{
+    io::{self, Write},
+    ops::Range,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One line per group header or record, indented like in the console.
+    Text,
+    /// One JSON object per record, with its level, message, key-value pairs and group chain.
+    JsonLines,
+    /// One row per record, with level, group chain, message and key-value pair columns.
+    Csv,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [Self::Text, Self::JsonLines, Self::Csv];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Text => "Text",
+            ExportFormat::JsonLines => "JSON Lines",
+            ExportFormat::Csv => "CSV",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Text => "txt",
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportScope {
+    /// Every record in the history.
+    All,
+    /// Only the records that match the current filters, or all of them if no filters are set.
+    Filtered,
+}
+
+fn write_json_str(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        let escaped = match c {
+            '"' => "\\\"",
+            '\\' => "\\\\",
+            '\n' => "\\n",
+            '\r' => "\\r",
+            '\t' => "\\t",
+            c if (c as u32) < 0x20 => {
+                writer.write_all(&value.as_bytes()[start..i])?;
+                write!(writer, "\\u{:04x}", c as u32)?;
+                start = i + 1;
+                continue;
+            }
+            _ => continue,
+        };
+        writer.write_all(&value.as_bytes()[start..i])?;
+        writer.write_all(escaped.as_bytes())?;
+        start = i + 1;
+    }
+    writer.write_all(&value.as_bytes()[start..])?;
+    writer.write_all(b"\"")
+}
+
+fn write_csv_field(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    if value.contains(['"', ',', '\n', '\r']) {
+        writer.write_all(b"\"")?;
+        writer.write_all(value.replace('"', "\"\"").as_bytes())?;
+        writer.write_all(b"\"")
+    } else {
+        writer.write_all(value.as_bytes())
+    }
+}
+
+fn group_chain<'a>(history: &'a History, leaf: &Leaf, chain: &mut Vec<&'a str>) {
+    chain.clear();
+    let mut parent_id = leaf.parent;
+    while parent_id != NodeId::MAX {
+        let group = &history.groups[parent_id];
+        chain.push(&group.kv_str);
+        parent_id = group.parent;
+    }
+    chain.reverse();
+}
+
+/// A record to export, from either the history or the spill file.
+struct Record<'a> {
+    indent: u16,
+    level: Level,
+    /// The message, followed by the formatted key-value pairs.
+    text: &'a str,
+    msg_len: usize,
+    /// The key, kind and position in `text` of the value of each key-value pair.
+    kv: Vec<(&'a str, ValueKind, Range<usize>)>,
+    groups: &'a [&'a str],
+}
+
+fn write_json_record(writer: &mut impl Write, record: &Record) -> io::Result<()> {
+    writer.write_all(b"{\"level\":")?;
+    write_json_str(writer, record.level.as_str())?;
+    writer.write_all(b",\"msg\":")?;
+    write_json_str(writer, &record.text[..record.msg_len])?;
+
+    writer.write_all(b",\"kv\":{")?;
+    for (i, (key, kind, value)) in record.kv.iter().enumerate() {
+        if i != 0 {
+            writer.write_all(b",")?;
+        }
+        write_json_str(writer, key)?;
+        writer.write_all(b":")?;
+        let value = &record.text[value.clone()];
+        match kind {
+            ValueKind::None | ValueKind::Unit => writer.write_all(b"null")?,
+            ValueKind::Bool | ValueKind::U64 | ValueKind::I64 => {
+                writer.write_all(value.as_bytes())?
+            }
+            ValueKind::F32 | ValueKind::F64 => {
+                if value.parse::<f64>().is_ok_and(f64::is_finite) {
+                    writer.write_all(value.as_bytes())?;
+                } else {
+                    write_json_str(writer, value)?;
+                }
+            }
+            ValueKind::Char | ValueKind::Str => write_json_str(writer, value)?,
+        }
+    }
+
+    writer.write_all(b"},\"groups\":[")?;
+    for (i, group) in record.groups.iter().enumerate() {
+        if i != 0 {
+            writer.write_all(b",")?;
+        }
+        let (key, value) = group.split_once(": ").unwrap_or((group, ""));
+        writer.write_all(b"{\"key\":")?;
+        write_json_str(writer, key)?;
+        writer.write_all(b",\"value\":")?;
+        write_json_str(writer, value)?;
+        writer.write_all(b"}")?;
+    }
+    writer.write_all(b"]}\n")
+}
+
+fn write_csv_record(writer: &mut impl Write, record: &Record) -> io::Result<()> {
+    writer.write_all(record.level.as_str().as_bytes())?;
+    writer.write_all(b",")?;
+    write_csv_field(writer, &record.groups.join("; "))?;
+    writer.write_all(b",")?;
+    write_csv_field(writer, &record.text[..record.msg_len])?;
+    writer.write_all(b",")?;
+    // Key-value pairs follow the message, separated from it by a comma if it's not empty
+    let kv = &record.text[record.msg_len..];
+    write_csv_field(writer, kv.strip_prefix(", ").unwrap_or(kv))?;
+    writer.write_all(b"\n")
+}
+
+fn write_group(
+    writer: &mut impl Write,
+    format: ExportFormat,
+    indent: u16,
+    text: &str,
+) -> io::Result<()> {
+    if format == ExportFormat::Text {
+        writeln!(
+            writer,
+            "{:indent$}{}",
+            "",
+            text,
+            indent = indent as usize * 2
+        )?;
+    }
+    Ok(())
+}
+
+fn write_record(writer: &mut impl Write, format: ExportFormat, record: &Record) -> io::Result<()> {
+    match format {
+        ExportFormat::Text => writeln!(
+            writer,
+            "{:indent$}{} {}",
+            "",
+            record.level.as_short_str(),
+            record.text,
+            indent = record.indent as usize * 2
+        ),
+        ExportFormat::JsonLines => write_json_record(writer, record),
+        ExportFormat::Csv => write_csv_record(writer, record),
+    }
+}
+
+/// Writes the given rows of the spill file (all of them, or the filtered ones), if any, and then
+/// the given nodes of the history.
+pub fn export(
+    history: &History,
+    nodes: &[Node],
+    spill: Option<(&mut Spill, bool)>,
+    mut writer: impl Write,
+    format: ExportFormat,
+) -> io::Result<()> {
+    if format == ExportFormat::Csv {
+        writer.write_all(b"level,groups,message,kv\n")?;
+    }
+
+    if let Some((spill, filtered)) = spill {
+        // Group rows are written before the rows nested in them, so the current chain of groups
+        // is the one any record row is in
+        let mut chain = Vec::<String>::new();
+        spill.for_each_row(filtered, |row, details| {
+            chain.truncate(row.indent as usize);
+            match row.level {
+                None => {
+                    write_group(&mut writer, format, row.indent, &row.text)?;
+                    chain.push(row.text.clone());
+                }
+
+                Some(level) => {
+                    let groups = chain.iter().map(String::as_str).collect::<Vec<_>>();
+                    write_record(
+                        &mut writer,
+                        format,
+                        &Record {
+                            indent: row.indent,
+                            level,
+                            text: &row.text,
+                            msg_len: details.msg_len as usize,
+                            kv: details
+                                .kv
+                                .iter()
+                                .map(|kv| (&*kv.key, kv.kind, kv.start as usize..kv.end as usize))
+                                .collect(),
+                            groups: &groups,
+                        },
+                    )?;
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut chain = Vec::new();
+    for node in nodes {
+        match node.kind {
+            NodeKind::Group => write_group(
+                &mut writer,
+                format,
+                node.indent,
+                &history.groups[node.id].kv_str,
+            )?,
+
+            NodeKind::Leaf => {
+                let leaf = &history.leaves[(node.id - history.cur_leaf_base_id) as usize];
+                if format != ExportFormat::Text {
+                    group_chain(history, leaf, &mut chain);
+                }
+                write_record(
+                    &mut writer,
+                    format,
+                    &Record {
+                        indent: node.indent,
+                        level: leaf.level,
+                        text: history.leaf_msg(leaf),
+                        msg_len: leaf.msg_len as usize,
+                        kv: leaf
+                            .kv
+                            .iter()
+                            .map(|kv| (kv.key, kv.kind, kv.start as usize..kv.end as usize))
+                            .collect(),
+                        groups: &chain,
+                    },
+                )?;
+            }
+        }
+    }
+
+    writer.flush()
+}
@@ -1,16 +1,16 @@
 use super::{history, Console, History};
 use std::mem::replace;
 
-type FilterAllFn = fn(&mut History, &mut FilterData);
+type ContinueRefilterFn = fn(&mut History, &FilterData, usize) -> bool;
 
-static FILTER_ALL_FNS: [FilterAllFn; 4] = [
-    Console::filter_all::<true, true>,
-    Console::filter_all::<true, false>,
-    Console::filter_all::<false, true>,
-    Console::filter_all::<false, false>,
+static CONTINUE_REFILTER_FNS: [ContinueRefilterFn; 4] = [
+    Console::continue_refilter_nodes::<true, true>,
+    Console::continue_refilter_nodes::<true, false>,
+    Console::continue_refilter_nodes::<false, true>,
+    Console::continue_refilter_nodes::<false, false>,
 ];
 
-type FilterNewMessageFn = fn(&mut Console, u16, history::NodeId, &history::Leaf);
+type FilterNewMessageFn = fn(&mut Console, u16, history::NodeId);
 static FILTER_NEW_MESSAGE_FNS: [FilterNewMessageFn; 4] = [
     Console::filter_new_message::<true, true>,
     Console::filter_new_message::<true, false>,
@@ -22,7 +22,8 @@ pub struct FilterData {
     filtering_enabled: bool,
     msg_filter: String,
     kv_filter: Vec<String>,
-    filter_all: FilterAllFn,
+    hidden_sources: Vec<bool>,
+    continue_refilter: ContinueRefilterFn,
     filter_new_message: FilterNewMessageFn,
 }
 
@@ -33,7 +34,8 @@ impl FilterData {
             filtering_enabled: !(msg_filter.is_empty() && kv_filter.is_empty()),
             msg_filter,
             kv_filter,
-            filter_all: FILTER_ALL_FNS[fn_key],
+            hidden_sources: Vec::new(),
+            continue_refilter: CONTINUE_REFILTER_FNS[fn_key],
             filter_new_message: FILTER_NEW_MESSAGE_FNS[fn_key],
         }
     }
@@ -43,10 +45,12 @@ impl FilterData {
     }
 
     fn update_filters(&mut self) {
-        self.filtering_enabled = !(self.msg_filter.is_empty() && self.kv_filter.is_empty());
+        self.filtering_enabled = !(self.msg_filter.is_empty()
+            && self.kv_filter.is_empty()
+            && !self.hidden_sources.contains(&true));
         let fn_key =
             (self.msg_filter.is_empty() as usize) << 1 | self.kv_filter.is_empty() as usize;
-        self.filter_all = FILTER_ALL_FNS[fn_key];
+        self.continue_refilter = CONTINUE_REFILTER_FNS[fn_key];
         self.filter_new_message = FILTER_NEW_MESSAGE_FNS[fn_key];
     }
 
@@ -70,8 +74,21 @@ impl FilterData {
         prev
     }
 
-    pub fn filter_all(&self) -> FilterAllFn {
-        self.filter_all
+    pub fn hidden_sources(&self) -> &[bool] {
+        &self.hidden_sources
+    }
+
+    pub fn set_source_hidden(&mut self, source: u16, hidden: bool) {
+        let i = source as usize;
+        if self.hidden_sources.len() <= i {
+            self.hidden_sources.resize(i + 1, false);
+        }
+        self.hidden_sources[i] = hidden;
+        self.update_filters();
+    }
+
+    pub fn continue_refilter(&self) -> ContinueRefilterFn {
+        self.continue_refilter
     }
 
     pub fn filter_new_message(&self) -> FilterNewMessageFn {
@@ -0,0 +1,299 @@
+use super::history::{Group, NodeId};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem::size_of,
+    ops::{Index, IndexMut},
+};
+
+const PAGE_SIZE: usize = 256;
+
+struct Page {
+    slots: Box<[Option<Group>]>,
+    len: usize,
+}
+
+impl Page {
+    fn new() -> Self {
+        Page {
+            slots: (0..PAGE_SIZE).map(|_| None).collect(),
+            len: 0,
+        }
+    }
+}
+
+/// Groups stored by ID, in pages of consecutive IDs.
+///
+/// Group IDs are never reused, so an ID also acts as the generation of its slot: once a group is
+/// removed, looking it up finds either an empty slot or a page that's been freed. Pages are freed
+/// as soon as all their groups are removed, so long-lived groups only keep their own page alive.
+///
+/// Most pages are kept in a deque covering the IDs from the oldest live page onwards; once most of
+/// it is empty (because a few long-lived groups with low IDs keep its front alive), its oldest
+/// pages are moved to a map, so that it doesn't keep growing with every new page.
+#[derive(Default)]
+pub struct GroupSlab {
+    /// Pages for the IDs starting at `base_id`, which is a multiple of `PAGE_SIZE`.
+    pages: VecDeque<Option<Page>>,
+    base_id: NodeId,
+    /// Pages for IDs below `base_id`, by their first ID.
+    sparse_pages: BTreeMap<NodeId, Page>,
+    allocated_pages: usize,
+    len: usize,
+}
+
+impl GroupSlab {
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.sparse_pages.clear();
+        self.allocated_pages = 0;
+        self.len = 0;
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn slot_pos(&self, id: NodeId) -> Option<(usize, usize)> {
+        let offset = usize::try_from(id.checked_sub(self.base_id)?).ok()?;
+        Some((offset / PAGE_SIZE, slot_i(id)))
+    }
+
+    #[inline]
+    pub fn get(&self, id: NodeId) -> Option<&Group> {
+        match self.slot_pos(id) {
+            Some((page, slot)) => self.pages.get(page)?.as_ref()?.slots[slot].as_ref(),
+            None => self.sparse_pages.get(&page_id(id))?.slots[slot_i(id)].as_ref(),
+        }
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut Group> {
+        match self.slot_pos(id) {
+            Some((page, slot)) => self.pages.get_mut(page)?.as_mut()?.slots[slot].as_mut(),
+            None => self.sparse_pages.get_mut(&page_id(id))?.slots[slot_i(id)].as_mut(),
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Inserts a group, replacing the one with the same ID if there's any.
+    pub fn insert(&mut self, id: NodeId, group: Group) {
+        if self.pages.is_empty()
+            && self
+                .sparse_pages
+                .last_key_value()
+                .is_none_or(|(&last_page_id, _)| last_page_id < page_id(id))
+        {
+            self.base_id = page_id(id);
+        }
+        let page = match self.slot_pos(id) {
+            Some((page_i, _)) => {
+                if page_i >= self.pages.len() {
+                    self.pages.resize_with(page_i + 1, || None);
+                }
+                self.pages[page_i].get_or_insert_with(|| {
+                    self.allocated_pages += 1;
+                    Page::new()
+                })
+            }
+            None => self.sparse_pages.entry(page_id(id)).or_insert_with(|| {
+                self.allocated_pages += 1;
+                Page::new()
+            }),
+        };
+        let slot = slot_i(id);
+        if page.slots[slot].replace(group).is_none() {
+            page.len += 1;
+            self.len += 1;
+        }
+    }
+
+    /// Removes the groups for which `f` returns `false`, freeing the pages that become empty.
+    pub fn retain(&mut self, mut f: impl FnMut(NodeId, &mut Group) -> bool) {
+        let mut retain_page = |page_base_id: NodeId, page: &mut Page| {
+            for (i, slot) in page.slots.iter_mut().enumerate() {
+                if let Some(group) = slot {
+                    if !f(page_base_id + i as NodeId, group) {
+                        *slot = None;
+                        page.len -= 1;
+                        self.len -= 1;
+                    }
+                }
+            }
+            page.len != 0
+        };
+
+        let sparse_len = self.sparse_pages.len();
+        self.sparse_pages
+            .retain(|&page_base_id, page| retain_page(page_base_id, page));
+        self.allocated_pages -= sparse_len - self.sparse_pages.len();
+
+        for (page_i, page_slot) in self.pages.iter_mut().enumerate() {
+            let Some(page) = page_slot else {
+                continue;
+            };
+            if !retain_page(self.base_id + (page_i * PAGE_SIZE) as NodeId, page) {
+                *page_slot = None;
+                self.allocated_pages -= 1;
+            }
+        }
+
+        self.pop_empty_front_pages();
+        // Once most of the deque is empty, the oldest pages are only keeping it from shrinking
+        while self.pages.len() > 2 * (self.allocated_pages - self.sparse_pages.len()) {
+            let page = self.pages.pop_front().unwrap().unwrap();
+            self.sparse_pages.insert(self.base_id, page);
+            self.base_id += PAGE_SIZE as NodeId;
+            self.pop_empty_front_pages();
+        }
+    }
+
+    fn pop_empty_front_pages(&mut self) {
+        while self.pages.front().is_some_and(Option::is_none) {
+            self.pages.pop_front();
+            self.base_id += PAGE_SIZE as NodeId;
+        }
+    }
+
+    /// Iterates over the groups in increasing ID order.
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Group)> {
+        self.sparse_pages
+            .iter()
+            .map(|(&page_base_id, page)| (page_base_id, page))
+            .chain(self.pages.iter().enumerate().filter_map(|(page_i, page)| {
+                Some((
+                    self.base_id + (page_i * PAGE_SIZE) as NodeId,
+                    page.as_ref()?,
+                ))
+            }))
+            .flat_map(|(page_base_id, page)| {
+                page.slots
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(i, slot)| Some((page_base_id + i as NodeId, slot.as_ref()?)))
+            })
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Group> {
+        self.sparse_pages
+            .values_mut()
+            .chain(self.pages.iter_mut().flatten())
+            .flat_map(|page| page.slots.iter_mut().flatten())
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.pages.len() * size_of::<Option<Page>>()
+            + self.sparse_pages.len() * size_of::<(NodeId, Page)>()
+            + self.allocated_pages * PAGE_SIZE * size_of::<Option<Group>>()
+    }
+}
+
+/// Returns the first ID of the page `id` is in.
+#[inline]
+fn page_id(id: NodeId) -> NodeId {
+    id - id % PAGE_SIZE as NodeId
+}
+
+#[inline]
+fn slot_i(id: NodeId) -> usize {
+    (id % PAGE_SIZE as NodeId) as usize
+}
+
+impl Index<NodeId> for GroupSlab {
+    type Output = Group;
+
+    #[inline]
+    fn index(&self, id: NodeId) -> &Group {
+        self.get(id).expect("invalid group ID")
+    }
+}
+
+impl IndexMut<NodeId> for GroupSlab {
+    #[inline]
+    fn index_mut(&mut self, id: NodeId) -> &mut Group {
+        self.get_mut(id).expect("invalid group ID")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(parent: NodeId) -> Group {
+        Group {
+            parent,
+            filtered_parent: parent,
+            ref_count: 1,
+            filtered_ref_count: 0,
+            kv_str: "a: 1".into(),
+        }
+    }
+
+    #[test]
+    fn insert_get_and_retain() {
+        let mut slab = GroupSlab::default();
+        for id in 300..1000 {
+            slab.insert(id, group(id));
+        }
+        assert_eq!(slab.len(), 700);
+        assert!(slab.get(299).is_none());
+        assert_eq!(slab[700].parent, 700);
+        slab[700].ref_count = 2;
+        assert_eq!(slab.get(700).unwrap().ref_count, 2);
+
+        slab.retain(|id, _| id % 3 == 0 || id >= 900);
+        assert_eq!(
+            slab.len(),
+            100 + (300..900).filter(|id| id % 3 == 0).count()
+        );
+        assert!(!slab.contains(301) && slab.contains(303) && slab.contains(901));
+        let ids: Vec<_> = slab.iter().map(|(id, _)| id).collect();
+        assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+        assert_eq!(ids.len(), slab.len());
+
+        slab.retain(|id, _| id >= 950);
+        assert_eq!(
+            slab.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            (950..1000).collect::<Vec<_>>()
+        );
+        assert_eq!(slab.pages.len(), 1);
+        slab.clear();
+        assert_eq!(slab.len(), 0);
+        assert!(slab.get(950).is_none());
+    }
+
+    #[test]
+    fn pinned_low_ids_dont_grow_pages() {
+        let mut slab = GroupSlab::default();
+        slab.insert(0, group(NodeId::MAX));
+        slab.insert(PAGE_SIZE as NodeId * 3 + 5, group(0));
+        // Groups are added and removed in a sliding window, while the first ones stay alive
+        for id in 1..100_000 {
+            slab.insert(id + 1000, group(0));
+            if id % 1000 == 0 {
+                slab.retain(|group_id, _| {
+                    group_id + 2000 > id + 1000 || group_id < PAGE_SIZE as NodeId * 4
+                });
+                assert!(slab.pages.len() <= 2 * (slab.allocated_pages - slab.sparse_pages.len()));
+            }
+        }
+        assert!(slab.pages.len() < 32, "{} pages", slab.pages.len());
+        assert!(slab.contains(0) && slab.contains(PAGE_SIZE as NodeId * 3 + 5));
+        let ids: Vec<_> = slab.iter().map(|(id, _)| id).collect();
+        assert!(ids.windows(2).all(|ids| ids[0] < ids[1]));
+        assert_eq!(ids.len(), slab.len());
+        assert_eq!(slab.values_mut().count(), slab.len());
+
+        // IDs below the deque go to the map too
+        slab.insert(1, group(0));
+        assert!(slab.contains(1));
+        slab.retain(|id, _| id != 0 && id != 1);
+        assert!(!slab.contains(0) && !slab.contains(1));
+        assert!(slab.contains(PAGE_SIZE as NodeId * 3 + 5));
+    }
+}
@@ -1,5 +1,20 @@
-use ahash::AHashMap as HashMap;
-use slog::Level;
+use super::{
+    arena::{TextArena, TextRef},
+    group_slab::GroupSlab,
+    trigram::TrigramIndex,
+};
+use ahash::AHashSet as HashSet;
+use slog::{Key, Level};
+use std::{
+    collections::VecDeque,
+    mem::{replace, size_of},
+    sync::Arc,
+};
+
+/// Leaves over their level's capacity are only removed once there are at least
+/// `1 / LEVEL_TRIM_DIVISOR` as many of them as the capacity (and at least one) for some level, since
+/// removing them takes a pass over the whole history.
+const LEVEL_TRIM_DIVISOR: usize = 16;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum NodeKind {
@@ -9,6 +24,17 @@ pub enum NodeKind {
 
 pub type NodeId = u64;
 
+/// Source ID of leaves whose records weren't processed from a named source.
+pub const NO_SOURCE: u16 = u16::MAX;
+
+#[inline]
+pub fn source_hidden(hidden_sources: &[bool], source: u16) -> bool {
+    hidden_sources
+        .get(source as usize)
+        .copied()
+        .unwrap_or(false)
+}
+
 #[derive(Clone, Copy)]
 pub struct Node {
     pub indent: u16,
@@ -22,7 +48,45 @@ pub struct Group {
     pub filtered_parent: NodeId,
     pub ref_count: u32,
     pub filtered_ref_count: u32,
-    pub kv_str: String,
+    /// Interned, so that groups with the same string share it and can be compared by pointer.
+    pub kv_str: Arc<str>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    None,
+    Unit,
+    Bool,
+    Char,
+    Str,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+impl ValueKind {
+    /// All kinds, indexed by their discriminants, for reading them back from binary formats.
+    pub const ALL: [ValueKind; 9] = [
+        ValueKind::None,
+        ValueKind::Unit,
+        ValueKind::Bool,
+        ValueKind::Char,
+        ValueKind::Str,
+        ValueKind::U64,
+        ValueKind::I64,
+        ValueKind::F32,
+        ValueKind::F64,
+    ];
+}
+
+/// A key-value pair of a leaf's record, with the value stored as text inside the leaf's `msg`.
+#[derive(Clone)]
+pub struct LeafKv {
+    pub key: Key,
+    pub kind: ValueKind,
+    pub start: u32,
+    pub end: u32,
 }
 
 #[derive(Clone)]
@@ -30,17 +94,63 @@ pub struct Leaf {
     pub parent: NodeId,
     pub filtered_parent: NodeId,
     pub level: Level,
-    pub msg: String,
+    pub source: u16,
+    pub removed: bool,
+    /// The record's message, followed by its formatted key-value pairs, stored in
+    /// `History::msgs`.
+    pub msg: TextRef,
+    /// The length of the message part of `msg`.
+    pub msg_len: u32,
+    pub kv: Box<[LeafKv]>,
+}
+
+impl Leaf {
+    /// Size of the leaf's own heap allocations, not counting its message.
+    pub fn heap_size(&self) -> usize {
+        self.kv.len() * size_of::<LeafKv>()
+    }
+}
+
+/// State of a refilter of the whole history that's done a chunk at a time.
+///
+/// The position reached is kept as the IDs of the next leaf and group to filter rather than as an
+/// index into `all`: both kinds of IDs increase along it, so they stay valid when nodes are
+/// removed from it or appended to it in the meantime.
+pub struct Refilter {
+    next_leaf_id: NodeId,
+    next_group_id: NodeId,
+    kv_filter_satisfied: Vec<(bool, u16)>,
+    /// Leaves and groups that can contain the message filter and each element of the key-value
+    /// filter, found with the index; ones added after the refilter started are always checked.
+    msg_candidates: Option<Vec<NodeId>>,
+    kv_candidates: Vec<Option<Vec<NodeId>>>,
+    first_new_leaf_id: NodeId,
+    first_new_group_id: NodeId,
+}
+
+#[inline]
+fn is_candidate(candidates: &Option<Vec<NodeId>>, first_new_id: NodeId, id: NodeId) -> bool {
+    id >= first_new_id
+        || candidates
+            .as_ref()
+            .is_none_or(|candidates| candidates.binary_search(&id).is_ok())
 }
 
 #[derive(Default)]
 pub struct History {
     pub next_group_id: NodeId,
-    pub groups: HashMap<NodeId, Group>,
+    pub groups: GroupSlab,
     pub cur_leaf_base_id: NodeId,
     pub leaves: Vec<Leaf>,
+    pub msgs: TextArena,
+    group_strs: HashSet<Arc<str>>,
     pub all: Vec<Node>,
     pub filtered: Vec<Node>,
+    pub level_leaves: [VecDeque<NodeId>; 6],
+    pub leaf_heap_bytes: usize,
+    pub group_str_bytes: usize,
+    pub refilter: Option<Refilter>,
+    pub index: Option<TrigramIndex>,
 }
 
 enum RetainUntilResult {
@@ -71,6 +181,7 @@ fn retain_until<T>(values: &mut Vec<T>, mut f: impl FnMut(&T) -> RetainUntilResu
                 }
             }
         }
+        values.set_len(dst_i);
     }
 }
 
@@ -78,7 +189,7 @@ macro_rules! increase_ref_count {
     ($init_parent_id: expr, $groups: expr, $ref_count: ident, $parent: ident) => {{
         let mut parent_id = $init_parent_id;
         while parent_id != NodeId::MAX {
-            let parent = $groups.get_mut(&parent_id).unwrap_unchecked();
+            let parent = &mut $groups[parent_id];
             parent.$ref_count += 1;
             if parent.$ref_count != 1 {
                 break;
@@ -92,7 +203,7 @@ macro_rules! decrease_ref_count {
     ($init_parent_id: expr, $groups: expr, $ref_count: ident, $parent: ident) => {{
         let mut parent_id = $init_parent_id;
         while parent_id != NodeId::MAX {
-            let parent = $groups.get_mut(&parent_id).unwrap_unchecked();
+            let parent = &mut $groups[parent_id];
             parent.$ref_count -= 1;
             if parent.$ref_count != 0 {
                 break;
@@ -102,18 +213,98 @@ macro_rules! decrease_ref_count {
     }};
 }
 
+macro_rules! remove_unreferenced_groups {
+    ($nodes: expr, $groups: expr, $ref_count: ident) => {
+        $nodes.retain(|node| match node.kind {
+            NodeKind::Leaf => true,
+            NodeKind::Group => $groups[node.id].$ref_count != 0,
+        })
+    };
+}
+
 impl History {
     pub fn clear(&mut self) {
         self.next_group_id = 0;
         self.groups.clear();
         self.cur_leaf_base_id = 0;
         self.leaves.clear();
+        self.msgs.clear();
+        self.group_strs.clear();
         self.all.clear();
         self.filtered.clear();
+        for level_leaves in &mut self.level_leaves {
+            level_leaves.clear();
+        }
+        self.leaf_heap_bytes = 0;
+        self.group_str_bytes = 0;
+        self.refilter = None;
+        if let Some(index) = &mut self.index {
+            index.clear();
+        }
+    }
+
+    /// Builds the trigram index from the current contents, if it isn't already enabled.
+    pub fn enable_index(&mut self) {
+        if self.index.is_some() {
+            return;
+        }
+        let mut index = TrigramIndex::default();
+        for (id, group) in self.groups.iter() {
+            index.add_group(id, &group.kv_str);
+        }
+        for (i, leaf) in self.leaves.iter().enumerate() {
+            if !leaf.removed {
+                index.add_leaf(self.cur_leaf_base_id + i as NodeId, self.msgs.get(leaf.msg));
+            }
+        }
+        self.index = Some(index);
+    }
+
+    #[inline]
+    pub fn leaf_msg(&self, leaf: &Leaf) -> &str {
+        self.msgs.get(leaf.msg)
+    }
+
+    /// Adds a group to the map and the index, interning its string.
+    pub fn insert_group(&mut self, id: NodeId, parent: NodeId, kv_str: &str) {
+        let kv_str = match self.group_strs.get(kv_str) {
+            Some(kv_str) => Arc::clone(kv_str),
+            None => {
+                let kv_str = Arc::<str>::from(kv_str);
+                self.group_str_bytes += kv_str.len();
+                self.group_strs.insert(Arc::clone(&kv_str));
+                kv_str
+            }
+        };
+        if let Some(index) = &mut self.index {
+            index.add_group(id, &kv_str);
+        }
+        self.groups.insert(
+            id,
+            Group {
+                parent,
+                filtered_parent: parent,
+                ref_count: 0,
+                filtered_ref_count: 0,
+                kv_str,
+            },
+        );
+    }
+
+    fn prune_index(&mut self) {
+        if let Some(index) = &mut self.index {
+            index.prune(
+                &self.leaves,
+                self.cur_leaf_base_id,
+                self.level_leaves.iter().map(VecDeque::len).sum(),
+                &self.groups,
+            );
+        }
     }
 
     pub fn clear_filtered(&mut self) {
         self.filtered.clear();
+        self.refilter = None;
 
         for group in self.groups.values_mut() {
             group.filtered_ref_count = 0;
@@ -129,6 +320,128 @@ impl History {
         self.cur_leaf_base_id + self.leaves.len() as NodeId
     }
 
+    pub fn push_leaf(&mut self, indent: u16, leaf: Leaf) {
+        let id = self.next_leaf_id();
+        self.leaf_heap_bytes += leaf.heap_size();
+        self.level_leaves[leaf.level.as_usize() - 1].push_back(id);
+        if let Some(index) = &mut self.index {
+            index.add_leaf(id, self.msgs.get(leaf.msg));
+        }
+        self.leaves.push(leaf);
+        self.all.push(Node {
+            indent,
+            kind: NodeKind::Leaf,
+            id,
+        });
+    }
+
+    /// Recomputes reference counts, per-level leaf lists and memory usage from `groups`, `leaves`
+    /// and `all` after they've been restored, dropping groups that don't contain any leaves; the
+    /// filtered list is left empty.
+    ///
+    /// # Safety
+    /// All leaf IDs in `all` must be valid.
+    pub unsafe fn rebuild_derived(&mut self) {
+        self.filtered.clear();
+        self.refilter = None;
+        for group in self.groups.values_mut() {
+            group.ref_count = 0;
+            group.filtered_ref_count = 0;
+            group.filtered_parent = group.parent;
+        }
+        for level_leaves in &mut self.level_leaves {
+            level_leaves.clear();
+        }
+        self.leaf_heap_bytes = 0;
+
+        for (i, leaf) in self.leaves.iter_mut().enumerate() {
+            leaf.filtered_parent = leaf.parent;
+            self.leaf_heap_bytes += leaf.heap_size();
+            if !leaf.removed {
+                self.level_leaves[leaf.level.as_usize() - 1]
+                    .push_back(self.cur_leaf_base_id + i as NodeId);
+            }
+        }
+        for node in &self.all {
+            if node.kind == NodeKind::Leaf {
+                increase_ref_count!(
+                    self.leaves
+                        .get_unchecked((node.id - self.cur_leaf_base_id) as usize)
+                        .parent,
+                    self.groups,
+                    ref_count,
+                    parent
+                );
+            }
+        }
+        remove_unreferenced_groups!(self.all, self.groups, ref_count);
+        self.remove_dead_groups();
+    }
+
+    /// Returns the number of leaves that haven't been removed yet.
+    pub fn live_leaf_count(&self) -> usize {
+        self.level_leaves.iter().map(VecDeque::len).sum()
+    }
+
+    /// Returns the position in `leaves` right after the `count` oldest leaves that haven't been
+    /// removed yet.
+    pub fn oldest_live_leaves_end(&self, count: usize) -> usize {
+        if count == 0 {
+            return 0;
+        }
+        if count > self.live_leaf_count() {
+            return self.leaves.len();
+        }
+        // Binary search for the position of the `count`th live leaf, counting the live leaves up
+        // to a position with the (sorted) per-level lists
+        let (mut low, mut high) = (0, self.leaves.len() - 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let end_id = self.cur_leaf_base_id + mid as NodeId + 1;
+            let live_count: usize = self
+                .level_leaves
+                .iter()
+                .map(|level_leaves| level_leaves.partition_point(|id| *id < end_id))
+                .sum();
+            if live_count >= count {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        low + 1
+    }
+
+    /// Approximate heap usage of the stored leaves, groups and node lists, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.leaves.len() * size_of::<Leaf>()
+            + self.leaf_heap_bytes
+            + self.groups.memory_usage()
+            + self.msgs.memory_usage()
+            + self.group_str_bytes
+            + (self.all.len() + self.filtered.len()) * size_of::<Node>()
+            + self.index.as_ref().map_or(0, TrigramIndex::memory_usage)
+    }
+
+    /// Returns how many of the oldest leaves need to be removed to free at least `bytes` bytes.
+    ///
+    /// Only the leaves themselves, their messages and their nodes in `all` are accounted for, so
+    /// the actual amount freed will usually be a bit different, since groups can be removed too and
+    /// messages are freed a whole chunk at a time.
+    pub fn leaves_to_free(&self, mut bytes: usize) -> usize {
+        let mut count = 0;
+        for leaf in &self.leaves {
+            if bytes == 0 {
+                break;
+            }
+            bytes = bytes.saturating_sub(
+                size_of::<Leaf>() + size_of::<Node>() + leaf.heap_size() + leaf.msg.len(),
+            );
+            count += 1;
+        }
+        count
+    }
+
     pub fn remove_leaves_before(&mut self, start_pos: usize) {
         let prev_leaf_base_id = self.cur_leaf_base_id;
         self.cur_leaf_base_id += start_pos as NodeId;
@@ -157,39 +470,177 @@ impl History {
                     NodeKind::Group => RetainUntilResult::Retain,
                 });
 
+                remove_unreferenced_groups!(self.$history, self.groups, $ref_count);
+            };
+        }
+
+        retain_nodes!(all, ref_count, parent);
+        retain_nodes!(filtered, filtered_ref_count, filtered_parent);
+
+        for level_leaves in &mut self.level_leaves {
+            while level_leaves
+                .front()
+                .is_some_and(|id| *id < self.cur_leaf_base_id)
+            {
+                level_leaves.pop_front();
+            }
+        }
+        for leaf in self.leaves.drain(..start_pos) {
+            self.leaf_heap_bytes -= leaf.heap_size();
+        }
+        self.msgs.release_before(
+            self.leaves
+                .iter()
+                .find(|leaf| !leaf.removed)
+                .map(|leaf| leaf.msg),
+        );
+        self.remove_dead_groups();
+        self.prune_index();
+    }
+
+    /// Removes the oldest leaves of each level that exceed the corresponding capacity (indexed by
+    /// `Level::as_usize() - 1`), regardless of their position in the history, once there are
+    /// enough of them (see `LEVEL_TRIM_DIVISOR`).
+    ///
+    /// Removed leaves are kept as tombstones in `leaves`, so that leaf IDs stay contiguous, until
+    /// they're dropped by `remove_leaves_before` or make up half of `leaves`, at which point they're
+    /// compacted out by `compact_leaves`.
+    pub fn remove_leaves_over_level_capacities(&mut self, capacities: &[usize; 6]) {
+        let trim_due = self
+            .level_leaves
+            .iter()
+            .zip(capacities)
+            .any(|(level_leaves, &capacity)| {
+                level_leaves.len().saturating_sub(capacity)
+                    >= (capacity / LEVEL_TRIM_DIVISOR).max(1)
+            });
+        if !trim_due {
+            return;
+        }
+
+        for (level_leaves, &capacity) in self.level_leaves.iter_mut().zip(capacities) {
+            while level_leaves.len() > capacity {
+                let id = level_leaves.pop_front().unwrap();
+                let leaf = &mut self.leaves[(id - self.cur_leaf_base_id) as usize];
+                leaf.removed = true;
+                self.leaf_heap_bytes -= leaf.heap_size();
+                leaf.msg = TextRef::EMPTY;
+                leaf.msg_len = 0;
+                leaf.kv = Box::new([]);
+            }
+        }
+
+        macro_rules! retain_nodes {
+            ($history: ident, $ref_count: ident, $parent: ident) => {
                 self.$history.retain(|node| match node.kind {
-                    NodeKind::Leaf => true,
-                    NodeKind::Group => unsafe {
-                        let group = self.groups.get(&node.id).unwrap_unchecked();
-                        if group.$ref_count == 0 {
-                            decrease_ref_count!(group.$parent, self.groups, $ref_count, $parent);
+                    NodeKind::Leaf => unsafe {
+                        let leaf = self
+                            .leaves
+                            .get_unchecked((node.id - self.cur_leaf_base_id) as usize);
+                        if leaf.removed {
+                            decrease_ref_count!(leaf.$parent, self.groups, $ref_count, $parent);
                             false
                         } else {
                             true
                         }
                     },
+                    NodeKind::Group => true,
                 });
+
+                remove_unreferenced_groups!(self.$history, self.groups, $ref_count);
             };
         }
 
         retain_nodes!(all, ref_count, parent);
         retain_nodes!(filtered, filtered_ref_count, filtered_parent);
 
-        self.leaves.drain(..start_pos);
-        self.groups.retain(|_, group| group.ref_count != 0);
+        self.remove_dead_groups();
+        if self.leaves.len() - self.live_leaf_count() > self.live_leaf_count() {
+            self.compact_leaves();
+        }
+        self.prune_index();
+        self.collapse_filtered_groups();
+    }
+
+    /// Drops the tombstones from `leaves`, renumbering the other leaves so that their IDs stay
+    /// contiguous, and moves their messages to a new arena, freeing the chunks that were only kept
+    /// for older leaves that aren't removed.
+    ///
+    /// Tombstones must already have been removed from `all` and `filtered`.
+    fn compact_leaves(&mut self) {
+        let base_id = self.cur_leaf_base_id;
+        let len = self.leaves.len();
+        // The new ID of the first live leaf at or after each position, including the end
+        let mut new_ids = Vec::with_capacity(len + 1);
+        let mut next_id = base_id;
+        let mut msgs = TextArena::default();
+        self.leaves.retain_mut(|leaf| {
+            new_ids.push(next_id);
+            if leaf.removed {
+                return false;
+            }
+            leaf.msg = msgs.push(self.msgs.get(leaf.msg));
+            next_id += 1;
+            true
+        });
+        new_ids.push(next_id);
+        self.msgs = msgs;
+
+        // IDs before the base are left alone, since they're still before every leaf
+        let remap_pos = |id: NodeId| match id.checked_sub(base_id) {
+            Some(i) => new_ids[(i as usize).min(len)],
+            None => id,
+        };
+        let remap_live = |id: NodeId| {
+            let i = id.checked_sub(base_id)? as usize;
+            (i < len && new_ids[i] != new_ids[i + 1]).then(|| new_ids[i])
+        };
+
+        for node in self.all.iter_mut().chain(&mut self.filtered) {
+            if node.kind == NodeKind::Leaf {
+                node.id = remap_pos(node.id);
+            }
+        }
+        for id in self.level_leaves.iter_mut().flatten() {
+            *id = remap_pos(*id);
+        }
+        if let Some(index) = &mut self.index {
+            index.remap_leaves(remap_live, self.leaves.len());
+        }
+        if let Some(refilter) = &mut self.refilter {
+            refilter.next_leaf_id = remap_pos(refilter.next_leaf_id);
+            refilter.first_new_leaf_id = remap_pos(refilter.first_new_leaf_id);
+            if let Some(candidates) = &mut refilter.msg_candidates {
+                *candidates = candidates.iter().filter_map(|id| remap_live(*id)).collect();
+            }
+        }
     }
 
-    fn filter_node<
-        'a,
-        const MSG_ENABLED: bool,
-        const KV_ENABLED: bool,
-        const INCREASE_REF_COUNT: bool,
-    >(
+    /// Removes groups that aren't referenced by either list anymore (groups that were merged into
+    /// from the filtered list can outlive their own children in `all`).
+    fn remove_dead_groups(&mut self) {
+        self.groups.retain(|_, group| {
+            if group.ref_count == 0 && group.filtered_ref_count == 0 {
+                // The only other reference is the interned one
+                if Arc::strong_count(&group.kv_str) == 2 {
+                    self.group_strs.remove(&group.kv_str);
+                    self.group_str_bytes -= group.kv_str.len();
+                }
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn filter_node<'a, const MSG_ENABLED: bool, const KV_ENABLED: bool>(
         leaves: &'a [Leaf],
         leaf_base_id: NodeId,
-        groups: &'a mut HashMap<NodeId, Group>,
+        msgs: &'a TextArena,
+        groups: &'a mut GroupSlab,
         msg_filter: &'a str,
         kv_filter: &'a [String],
+        hidden_sources: &'a [bool],
     ) -> impl FnMut(&Node) -> bool + 'a {
         let mut kv_filter_satisfied = vec![(false, 0); kv_filter.len()];
 
@@ -208,17 +659,9 @@ impl History {
                         let leaf = leaves.get_unchecked((node.id - leaf_base_id) as usize);
                         let filter_satisfied = (!KV_ENABLED
                             || kv_filter_satisfied.iter().all(|(satisfied, _)| *satisfied))
-                            && (!MSG_ENABLED || leaf.msg.contains(msg_filter));
-                        if filter_satisfied {
-                            if INCREASE_REF_COUNT {
-                                increase_ref_count!(
-                                    leaf.filtered_parent,
-                                    groups,
-                                    filtered_ref_count,
-                                    filtered_parent
-                                );
-                            }
-                        } else if !INCREASE_REF_COUNT {
+                            && (!MSG_ENABLED || msgs.get(leaf.msg).contains(msg_filter))
+                            && !source_hidden(hidden_sources, leaf.source);
+                        if !filter_satisfied {
                             decrease_ref_count!(
                                 leaf.filtered_parent,
                                 groups,
@@ -231,7 +674,7 @@ impl History {
 
                     NodeKind::Group => {
                         if KV_ENABLED {
-                            let group = groups.get(&node.id).unwrap_unchecked();
+                            let group = &groups[node.id];
                             for (i, (satisfied, satisfied_indent)) in
                                 kv_filter_satisfied.iter_mut().enumerate()
                             {
@@ -250,60 +693,200 @@ impl History {
     }
 
     pub fn apply_msg_filter_restriction(&mut self, new: &str) {
-        self.filtered
-            .retain(Self::filter_node::<true, false, false>(
-                &self.leaves,
-                self.cur_leaf_base_id,
-                &mut self.groups,
-                new,
-                &[],
-            ));
+        self.filtered.retain(Self::filter_node::<true, false>(
+            &self.leaves,
+            self.cur_leaf_base_id,
+            &self.msgs,
+            &mut self.groups,
+            new,
+            &[],
+            &[],
+        ));
     }
 
     pub fn apply_kv_filter_restriction(&mut self, new: &[String]) {
-        self.filtered
-            .retain(Self::filter_node::<false, true, false>(
-                &self.leaves,
-                self.cur_leaf_base_id,
-                &mut self.groups,
-                "",
-                new,
-            ));
+        self.filtered.retain(Self::filter_node::<false, true>(
+            &self.leaves,
+            self.cur_leaf_base_id,
+            &self.msgs,
+            &mut self.groups,
+            "",
+            new,
+            &[],
+        ));
     }
 
-    pub fn filter_all<const MSG_ENABLED: bool, const KV_ENABLED: bool>(
+    /// Starts a refilter of the whole history, clearing the filtered list; it's done by
+    /// `continue_refilter`, and new leaves shouldn't be passed to `filter_new_message` until it's
+    /// finished.
+    pub fn start_refilter(&mut self, msg_filter: &str, kv_filter: &[String]) {
+        self.clear_filtered();
+        let (msg_candidates, kv_candidates) = match &self.index {
+            Some(index) => (
+                index.leaf_candidates(msg_filter),
+                kv_filter
+                    .iter()
+                    .map(|elem| index.group_candidates(elem))
+                    .collect(),
+            ),
+            None => (None, vec![None; kv_filter.len()]),
+        };
+        self.refilter = Some(Refilter {
+            next_leaf_id: 0,
+            next_group_id: 0,
+            kv_filter_satisfied: vec![(false, 0); kv_filter.len()],
+            msg_candidates,
+            kv_candidates,
+            first_new_leaf_id: self.next_leaf_id(),
+            first_new_group_id: self.next_group_id,
+        });
+    }
+
+    #[inline]
+    pub fn refiltering(&self) -> bool {
+        self.refilter.is_some()
+    }
+
+    fn refilter_pos(&self, refilter: &Refilter) -> usize {
+        self.all.partition_point(|node| match node.kind {
+            NodeKind::Leaf => node.id < refilter.next_leaf_id,
+            NodeKind::Group => node.id < refilter.next_group_id,
+        })
+    }
+
+    /// Returns the number of nodes of `all` that have been filtered so far by the refilter in
+    /// progress, if any, and the length of `all`.
+    pub fn refilter_progress(&self) -> Option<(usize, usize)> {
+        let refilter = self.refilter.as_ref()?;
+        Some((self.refilter_pos(refilter), self.all.len()))
+    }
+
+    /// Filters up to `max_nodes` more nodes for the refilter in progress, appending the leaves that
+    /// pass to `filtered` along with their groups; returns whether the refilter is finished.
+    pub fn continue_refilter<const MSG_ENABLED: bool, const KV_ENABLED: bool>(
         &mut self,
         msg_filter: &str,
         kv_filter: &[String],
-    ) {
-        if !(MSG_ENABLED || KV_ENABLED) {
-            return;
+        hidden_sources: &[bool],
+        max_nodes: usize,
+    ) -> bool {
+        let Some(mut refilter) = self.refilter.take() else {
+            return true;
+        };
+        let start = self.refilter_pos(&refilter);
+        let end = start.saturating_add(max_nodes).min(self.all.len());
+
+        for node in &self.all[start..end] {
+            if KV_ENABLED {
+                for (satisfied, satisfied_indent) in &mut refilter.kv_filter_satisfied {
+                    if node.indent <= *satisfied_indent {
+                        *satisfied = false;
+                    }
+                }
+            }
+
+            unsafe {
+                match node.kind {
+                    NodeKind::Leaf => {
+                        let leaf = self
+                            .leaves
+                            .get_unchecked((node.id - self.cur_leaf_base_id) as usize);
+                        let filter_satisfied = (!KV_ENABLED
+                            || refilter
+                                .kv_filter_satisfied
+                                .iter()
+                                .all(|(satisfied, _)| *satisfied))
+                            && (!MSG_ENABLED
+                                || (is_candidate(
+                                    &refilter.msg_candidates,
+                                    refilter.first_new_leaf_id,
+                                    node.id,
+                                ) && self.msgs.get(leaf.msg).contains(msg_filter)))
+                            && !source_hidden(hidden_sources, leaf.source);
+                        if filter_satisfied {
+                            Self::push_filtered_leaf(
+                                &mut self.filtered,
+                                &mut self.groups,
+                                node.indent,
+                                node.id,
+                                leaf.filtered_parent,
+                            );
+                        }
+                        refilter.next_leaf_id = node.id + 1;
+                    }
+
+                    NodeKind::Group => {
+                        if KV_ENABLED {
+                            let group = &self.groups[node.id];
+                            for (i, (satisfied, satisfied_indent)) in
+                                refilter.kv_filter_satisfied.iter_mut().enumerate()
+                            {
+                                if !*satisfied
+                                    && is_candidate(
+                                        refilter.kv_candidates.get_unchecked(i),
+                                        refilter.first_new_group_id,
+                                        node.id,
+                                    )
+                                    && group.kv_str.contains(kv_filter.get_unchecked(i))
+                                {
+                                    *satisfied = true;
+                                    *satisfied_indent = node.indent;
+                                }
+                            }
+                        }
+                        refilter.next_group_id = node.id + 1;
+                    }
+                }
+            }
         }
 
-        self.clear_filtered();
+        if end == self.all.len() {
+            self.collapse_filtered_groups();
+            true
+        } else {
+            self.refilter = Some(refilter);
+            false
+        }
+    }
 
-        let mut filter_node = Self::filter_node::<MSG_ENABLED, KV_ENABLED, true>(
-            &self.leaves,
-            self.cur_leaf_base_id,
-            &mut self.groups,
-            msg_filter,
-            kv_filter,
-        );
-        self.filtered
-            .extend(self.all.iter().filter(move |node| filter_node(*node)));
+    /// Appends a leaf that passed the filters to `filtered`, inserting the groups it's in that
+    /// don't have any other filtered children yet right before it.
+    fn push_filtered_leaf(
+        filtered: &mut Vec<Node>,
+        groups: &mut GroupSlab,
+        mut indent: u16,
+        id: NodeId,
+        filtered_parent: NodeId,
+    ) {
+        let pos = filtered.len();
+        filtered.push(Node {
+            indent,
+            kind: NodeKind::Leaf,
+            id,
+        });
+
+        let mut parent_id = filtered_parent;
+        while parent_id != NodeId::MAX {
+            let parent = &mut groups[parent_id];
+            parent.filtered_ref_count += 1;
+            if parent.filtered_ref_count != 1 {
+                break;
+            }
+            indent -= 1;
+            filtered.insert(
+                pos,
+                Node {
+                    indent,
+                    kind: NodeKind::Group,
+                    id: parent_id,
+                },
+            );
+            parent_id = parent.filtered_parent;
+        }
     }
 
     fn remove_unreferenced_filtered_groups(&mut self) {
-        self.filtered.retain(|node| match node.kind {
-            NodeKind::Leaf => true,
-            NodeKind::Group => unsafe {
-                self.groups
-                    .get(&node.id)
-                    .unwrap_unchecked()
-                    .filtered_ref_count
-                    != 0
-            },
-        });
+        remove_unreferenced_groups!(self.filtered, self.groups, filtered_ref_count);
     }
 
     fn collapse_filtered_groups(&mut self) {
@@ -321,18 +904,31 @@ impl History {
 
                 while i < self.filtered.len() {
                     let node = &self.filtered[i];
-                    if node.indent < min_indent {
+                    if node.indent < min_indent
+                        || (node.kind == NodeKind::Leaf && node.indent <= cur_indent)
+                    {
                         cur_kv_str = None;
                     } else if node.kind == NodeKind::Group && node.indent <= cur_indent {
-                        let group = self.groups.get_mut(&node.id).unwrap_unchecked();
+                        let group = &mut self.groups[node.id];
                         found_groups = true;
 
-                        if node.indent < cur_indent || cur_kv_str.as_ref() != Some(&group.kv_str) {
-                            cur_kv_str = Some(group.kv_str.clone());
+                        if node.indent < cur_indent
+                            || !cur_kv_str
+                                .as_ref()
+                                .is_some_and(|kv_str| Arc::ptr_eq(kv_str, &group.kv_str))
+                        {
+                            cur_kv_str = Some(Arc::clone(&group.kv_str));
                             cur_indent = node.indent;
                             cur_id = node.id;
                         } else {
-                            group.filtered_ref_count = 0;
+                            // Hand this group's children over to the one it's being merged into,
+                            // and drop it from its parent's children.
+                            let children = replace(&mut group.filtered_ref_count, 0);
+                            let parent_id = group.filtered_parent;
+                            self.groups[cur_id].filtered_ref_count += children;
+                            if parent_id != NodeId::MAX {
+                                self.groups[parent_id].filtered_ref_count -= 1;
+                            }
 
                             {
                                 let copy_len = i - copy_src_i;
@@ -347,17 +943,14 @@ impl History {
                             }
 
                             let children_indent = cur_indent + 1;
-                            for node in &mut self.filtered[i..] {
+                            for node in &mut self.filtered[i + 1..] {
                                 if node.indent < children_indent {
                                     break;
                                 }
                                 if node.indent == children_indent {
                                     match node.kind {
                                         NodeKind::Group => {
-                                            self.groups
-                                                .get_mut(&node.id)
-                                                .unwrap_unchecked()
-                                                .filtered_parent = cur_id;
+                                            self.groups[node.id].filtered_parent = cur_id;
                                         }
 
                                         NodeKind::Leaf => {
@@ -400,19 +993,23 @@ impl History {
 
     pub fn filter_new_message<const MSG_ENABLED: bool, const KV_ENABLED: bool>(
         &mut self,
-        mut indent: u16,
+        indent: u16,
         id: NodeId,
-        leaf: &Leaf,
         msg_filter: &str,
         kv_filter: &[String],
+        hidden_sources: &[bool],
     ) {
+        let leaf = unsafe {
+            self.leaves
+                .get_unchecked((id - self.cur_leaf_base_id) as usize)
+        };
         let filter_satisfied = (!KV_ENABLED
             || unsafe {
                 let mut kv_filter_satisfied = vec![false; kv_filter.len()];
 
                 let mut parent_id = leaf.parent;
                 while parent_id != NodeId::MAX {
-                    let parent = self.groups.get_mut(&parent_id).unwrap_unchecked();
+                    let parent = &self.groups[parent_id];
                     for (i, filter) in kv_filter.iter().enumerate() {
                         if parent.kv_str.contains(filter) {
                             *kv_filter_satisfied.get_unchecked_mut(i) = true;
@@ -423,38 +1020,19 @@ impl History {
 
                 kv_filter_satisfied.iter().all(|v| *v)
             })
-            && (!MSG_ENABLED || leaf.msg.contains(msg_filter));
+            && (!MSG_ENABLED || self.msgs.get(leaf.msg).contains(msg_filter))
+            && !source_hidden(hidden_sources, leaf.source);
         if !filter_satisfied {
             return;
         }
 
-        let pos = self.filtered.len();
-        self.filtered.push(Node {
+        Self::push_filtered_leaf(
+            &mut self.filtered,
+            &mut self.groups,
             indent,
-            kind: NodeKind::Leaf,
             id,
-        });
-
-        unsafe {
-            let mut parent_id = leaf.filtered_parent;
-            while parent_id != NodeId::MAX {
-                let parent = self.groups.get_mut(&parent_id).unwrap_unchecked();
-                parent.filtered_ref_count += 1;
-                if parent.filtered_ref_count != 1 {
-                    break;
-                }
-                indent -= 1;
-                self.filtered.insert(
-                    pos,
-                    Node {
-                        indent,
-                        kind: NodeKind::Group,
-                        id: parent_id,
-                    },
-                );
-                parent_id = parent.filtered_parent;
-            }
-        }
+            leaf.filtered_parent,
+        );
 
         self.collapse_filtered_groups();
     }
@@ -1,6 +1,6 @@
 use super::{history, History};
 use slog::{ser::Serializer, Key};
-use std::fmt;
+use std::fmt::{self, Write as _};
 
 type StringKV = (Key, String);
 
@@ -8,6 +8,7 @@ type StringKV = (Key, String);
 pub struct LoggerKVGroupsSerializer {
     cur_kv_groups: Vec<(history::NodeId, StringKV)>,
     kv_buf: Vec<StringKV>,
+    kv_str_buf: String,
 }
 
 impl LoggerKVGroupsSerializer {
@@ -23,29 +24,30 @@ impl LoggerKVGroupsSerializer {
             return (0, parent);
         }
 
+        let len = self.kv_buf.len();
         for (i, kv) in self.kv_buf.drain(..).rev().enumerate() {
             if let Some((id, prev_kv)) = self.cur_kv_groups.get(i) {
-                if *prev_kv == kv {
+                // The group can have been evicted along with all of its leaves since, in which
+                // case it's not in `all` anymore (and possibly not in `groups` either)
+                if *prev_kv == kv
+                    && history
+                        .groups
+                        .get(*id)
+                        .is_some_and(|group| group.ref_count != 0)
+                {
                     parent = *id;
                     continue;
                 }
             }
 
             if parent != history::NodeId::MAX {
-                history.groups.get_mut(&parent).unwrap().ref_count += 1;
+                history.groups[parent].ref_count += 1;
             }
 
             let id = history.next_group_id;
-            history.groups.insert(
-                id,
-                history::Group {
-                    parent,
-                    filtered_parent: parent,
-                    ref_count: 0,
-                    filtered_ref_count: 0,
-                    kv_str: format!("{}: {}", kv.0, kv.1),
-                },
-            );
+            self.kv_str_buf.clear();
+            let _ = write!(self.kv_str_buf, "{}: {}", kv.0, kv.1);
+            history.insert_group(id, parent, &self.kv_str_buf);
             history.next_group_id += 1;
 
             history.all.push(history::Node {
@@ -58,7 +60,8 @@ impl LoggerKVGroupsSerializer {
             self.cur_kv_groups.push((id, kv));
             parent = id;
         }
-        (self.cur_kv_groups.len() as u16, parent)
+        self.cur_kv_groups.truncate(len);
+        (len as u16, parent)
     }
 }
 
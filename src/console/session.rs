@@ -0,0 +1,441 @@
+use super::{
+    arena::{TextArena, TextRef},
+    history::{History, Leaf, LeafKv, Node, NodeId, NodeKind, ValueKind, NO_SOURCE},
+};
+use crate::{
+    binary::{
+        capacity_hint, invalid_data, read_f32, read_string, read_u16, read_u32, read_u64, read_u8,
+        write_f32, write_str, write_u16, write_u32, write_u64, write_u8,
+    },
+    intern::{try_intern, FALLBACK_KEY},
+};
+use ahash::{AHashMap as HashMap, AHashSet as HashSet};
+use slog::Level;
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 8] = *b"SLOGLOG\0";
+const VERSION: u32 = 1;
+
+/// The console state stored in a session file alongside the history.
+pub struct ViewState {
+    pub locked_to_bottom: bool,
+    pub msg_filter: String,
+    pub kv_filter: Vec<String>,
+    /// The name and color of each source, and whether it's hidden.
+    pub sources: Vec<(String, [f32; 4], bool)>,
+}
+
+pub fn save(history: &History, view: &ViewState, mut writer: impl Write) -> io::Result<()> {
+    let writer = &mut writer;
+    writer.write_all(&MAGIC)?;
+    write_u32(writer, VERSION)?;
+
+    write_u8(writer, view.locked_to_bottom as u8)?;
+    write_str(writer, &view.msg_filter)?;
+    write_u32(writer, view.kv_filter.len() as u32)?;
+    for filter in &view.kv_filter {
+        write_str(writer, filter)?;
+    }
+    write_u16(writer, view.sources.len() as u16)?;
+    for (name, color, hidden) in &view.sources {
+        write_str(writer, name)?;
+        for &component in color {
+            write_f32(writer, component)?;
+        }
+        write_u8(writer, *hidden as u8)?;
+    }
+
+    write_u64(writer, history.next_group_id)?;
+    // Groups that no leaf is nested in anymore are only kept around for the filtered list, which
+    // gets rebuilt on load anyway
+    let groups = history
+        .groups
+        .iter()
+        .filter(|(_, group)| group.ref_count != 0)
+        .collect::<Vec<_>>();
+    write_u64(writer, groups.len() as u64)?;
+    for (id, group) in groups {
+        write_u64(writer, id)?;
+        write_u64(writer, group.parent)?;
+        write_str(writer, &group.kv_str)?;
+    }
+
+    write_u64(writer, history.cur_leaf_base_id)?;
+    write_u64(writer, history.leaves.len() as u64)?;
+    for leaf in &history.leaves {
+        // Removed leaves' groups might not exist anymore
+        write_u64(
+            writer,
+            if leaf.removed {
+                NodeId::MAX
+            } else {
+                leaf.parent
+            },
+        )?;
+        write_u8(writer, leaf.level.as_usize() as u8)?;
+        write_u16(writer, leaf.source)?;
+        write_u8(writer, leaf.removed as u8)?;
+        if leaf.removed {
+            continue;
+        }
+        write_str(writer, history.leaf_msg(leaf))?;
+        write_u32(writer, leaf.msg_len)?;
+        write_u32(writer, leaf.kv.len() as u32)?;
+        for kv in leaf.kv.iter() {
+            write_str(writer, kv.key)?;
+            write_u8(writer, kv.kind as u8)?;
+            write_u32(writer, kv.start)?;
+            write_u32(writer, kv.end)?;
+        }
+    }
+
+    write_u64(writer, history.all.len() as u64)?;
+    for node in &history.all {
+        write_u16(writer, node.indent)?;
+        write_u8(writer, (node.kind == NodeKind::Group) as u8)?;
+        write_u64(writer, node.id)?;
+    }
+
+    writer.flush()
+}
+
+fn read_bool(reader: &mut impl Read) -> io::Result<bool> {
+    match read_u8(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid_data("invalid boolean")),
+    }
+}
+
+fn read_leaf(
+    reader: &mut impl Read,
+    group_ids: &HashMap<NodeId, NodeId>,
+    msgs: &mut TextArena,
+    sources_len: usize,
+) -> io::Result<Leaf> {
+    let mut parent = read_u64(reader)?;
+    if parent != NodeId::MAX {
+        parent = *group_ids
+            .get(&parent)
+            .ok_or_else(|| invalid_data("invalid leaf parent"))?;
+    }
+    let level = Level::from_usize(read_u8(reader)? as usize)
+        .ok_or_else(|| invalid_data("invalid level"))?;
+    let source = read_u16(reader)?;
+    if source != NO_SOURCE && source as usize >= sources_len {
+        return Err(invalid_data("invalid leaf source"));
+    }
+    let removed = read_bool(reader)?;
+
+    let mut leaf = Leaf {
+        parent,
+        filtered_parent: parent,
+        level,
+        source,
+        removed,
+        msg: TextRef::EMPTY,
+        msg_len: 0,
+        kv: Box::new([]),
+    };
+    if removed {
+        return Ok(leaf);
+    }
+
+    let msg = read_string(reader)?;
+    leaf.msg_len = read_u32(reader)?;
+    if !msg.is_char_boundary(leaf.msg_len as usize) {
+        return Err(invalid_data("invalid message length"));
+    }
+
+    let kv_len = read_u32(reader)? as u64;
+    let mut kv = Vec::with_capacity(capacity_hint(kv_len));
+    for _ in 0..kv_len {
+        let key = read_string(reader)?;
+        let mut kind = *ValueKind::ALL
+            .get(read_u8(reader)? as usize)
+            .ok_or_else(|| invalid_data("invalid value kind"))?;
+        let mut start = read_u32(reader)?;
+        let end = read_u32(reader)?;
+        if start < leaf.msg_len
+            || start > end
+            || !msg.is_char_boundary(start as usize)
+            || !msg.is_char_boundary(end as usize)
+        {
+            return Err(invalid_data("invalid key-value pair span"));
+        }
+        // Session files can come from anywhere, so their keys are only interned up to a limit;
+        // past it, the value is extended back over the key in the message when it's there
+        let key = match try_intern(&key) {
+            Some(key) => key,
+            None => {
+                if let Some(key_start) = msg[..start as usize]
+                    .strip_suffix(": ")
+                    .and_then(|prefix| prefix.strip_suffix(key.as_str()))
+                    .map(str::len)
+                    .filter(|&key_start| key_start >= leaf.msg_len as usize)
+                {
+                    start = key_start as u32;
+                    kind = ValueKind::Str;
+                }
+                FALLBACK_KEY
+            }
+        };
+        kv.push(LeafKv {
+            key,
+            kind,
+            start,
+            end,
+        });
+    }
+    leaf.kv = kv.into_boxed_slice();
+    leaf.msg = msgs.push(&msg);
+    Ok(leaf)
+}
+
+/// Reads a session written by `save`, checking that it describes a consistent tree so that the
+/// history's invariants hold for it.
+pub fn load(mut reader: impl Read) -> io::Result<(History, ViewState)> {
+    let reader = &mut reader;
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data("not a session file"));
+    }
+    if read_u32(reader)? != VERSION {
+        return Err(invalid_data("unsupported session file version"));
+    }
+
+    let locked_to_bottom = read_bool(reader)?;
+    let msg_filter = read_string(reader)?;
+    let kv_filter_len = read_u32(reader)? as u64;
+    let mut kv_filter = Vec::with_capacity(capacity_hint(kv_filter_len));
+    for _ in 0..kv_filter_len {
+        kv_filter.push(read_string(reader)?);
+    }
+    let sources_len = read_u16(reader)?;
+    if sources_len == NO_SOURCE {
+        return Err(invalid_data("too many sources"));
+    }
+    let mut sources = Vec::with_capacity(sources_len as usize);
+    for _ in 0..sources_len {
+        let name = read_string(reader)?;
+        let mut color = [0.0; 4];
+        for component in &mut color {
+            *component = read_f32(reader)?;
+        }
+        sources.push((name, color, read_bool(reader)?));
+    }
+
+    let mut history = History::default();
+    let next_group_id = read_u64(reader)?;
+
+    // Groups are renumbered from 0 in the order they're written in, which is their ID order, so
+    // that the IDs of groups removed before saving don't take up any room in the history. They're
+    // always created after their parent, so checking that parents come first also rules out
+    // cycles.
+    let groups_len = read_u64(reader)?;
+    let mut group_ids = HashMap::with_capacity(capacity_hint(groups_len));
+    let mut min_group_id = 0;
+    for _ in 0..groups_len {
+        let id = read_u64(reader)?;
+        let mut parent = read_u64(reader)?;
+        let kv_str = read_string(reader)?;
+        if id < min_group_id || id >= next_group_id {
+            return Err(invalid_data("invalid group ID"));
+        }
+        if parent != NodeId::MAX {
+            parent = *group_ids
+                .get(&parent)
+                .ok_or_else(|| invalid_data("invalid group parent"))?;
+        }
+        min_group_id = id + 1;
+        group_ids.insert(id, history.next_group_id);
+        history.insert_group(history.next_group_id, parent, &kv_str);
+        history.next_group_id += 1;
+    }
+
+    history.cur_leaf_base_id = read_u64(reader)?;
+    let leaves_len = read_u64(reader)?;
+    if history.cur_leaf_base_id.checked_add(leaves_len).is_none() {
+        return Err(invalid_data("invalid leaf count"));
+    }
+    history.leaves.reserve(capacity_hint(leaves_len));
+    for _ in 0..leaves_len {
+        let leaf = read_leaf(reader, &group_ids, &mut history.msgs, sources.len())?;
+        history.leaves.push(leaf);
+    }
+
+    // The node list needs to be a pre-order traversal of the tree, with leaves in ID order and
+    // each node nested in its parent, which has to be the last group seen at the previous indent.
+    let mut group_stack: Vec<NodeId> = Vec::new();
+    let mut groups_seen = HashSet::new();
+    let mut next_min_leaf_id = history.cur_leaf_base_id;
+    let mut live_leaves = history.leaves.iter().filter(|leaf| !leaf.removed).count();
+    let all_len = read_u64(reader)?;
+    history.all.reserve(capacity_hint(all_len));
+    for _ in 0..all_len {
+        let indent = read_u16(reader)?;
+        let is_group = read_bool(reader)?;
+        let mut id = read_u64(reader)?;
+
+        if indent as usize > group_stack.len() {
+            return Err(invalid_data("invalid node indent"));
+        }
+        group_stack.truncate(indent as usize);
+        let expected_parent = group_stack.last().copied().unwrap_or(NodeId::MAX);
+
+        let valid = if is_group {
+            match group_ids.get(&id) {
+                Some(&new_id) => {
+                    id = new_id;
+                    history.groups[id].parent == expected_parent && groups_seen.insert(id)
+                }
+                None => false,
+            }
+        } else {
+            id >= next_min_leaf_id
+                && history
+                    .leaves
+                    .get(usize::try_from(id - history.cur_leaf_base_id).unwrap_or(usize::MAX))
+                    .is_some_and(|leaf| !leaf.removed && leaf.parent == expected_parent)
+        };
+        if !valid {
+            return Err(invalid_data("invalid node"));
+        }
+
+        if is_group {
+            group_stack.push(id);
+        } else {
+            next_min_leaf_id = id + 1;
+            live_leaves -= 1;
+        }
+        history.all.push(Node {
+            indent,
+            kind: if is_group {
+                NodeKind::Group
+            } else {
+                NodeKind::Leaf
+            },
+            id,
+        });
+    }
+    if live_leaves != 0 {
+        return Err(invalid_data("missing leaf nodes"));
+    }
+
+    // SAFETY: All IDs have been checked above.
+    unsafe {
+        history.rebuild_derived();
+    }
+
+    Ok((
+        history,
+        ViewState {
+            locked_to_bottom,
+            msg_filter,
+            kv_filter,
+            sources,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Builder, Console};
+    use super::*;
+    use slog::{Record, RecordLocation, RecordStatic, Serializer, KV};
+
+    struct Pair(&'static str, &'static str);
+
+    impl KV for Pair {
+        fn serialize(&self, _record: &Record, serializer: &mut dyn Serializer) -> slog::Result {
+            serializer.emit_str(self.0, self.1)
+        }
+    }
+
+    fn log(console: &mut Console, msg: &str, kv: Pair) {
+        static LOCATION: RecordLocation = RecordLocation {
+            file: "",
+            line: 0,
+            column: 0,
+            function: "",
+            module: "",
+        };
+        let record_static = RecordStatic {
+            location: &LOCATION,
+            tag: "",
+            level: Level::Info,
+        };
+        console
+            .process_sync(std::iter::once((
+                &Record::new(
+                    &record_static,
+                    &format_args!("{msg}"),
+                    slog::BorrowedKV(&kv),
+                ),
+                &slog::o!("group" => 1).into(),
+            )))
+            .unwrap();
+    }
+
+    fn reload(console: &Console) -> Console {
+        let mut data = Vec::new();
+        console.save_session(&mut data).unwrap();
+        let mut loaded = Builder::new().build();
+        loaded.load_session(&data[..]).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut console = Builder::new().build();
+        console.set_msg_filter("second".to_string());
+        log(&mut console, "first", Pair("key", "value"));
+        log(&mut console, "second", Pair("key", "other value"));
+
+        let loaded = reload(&console);
+        assert_eq!(loaded.msg_filter(), "second");
+        let history = &loaded.history;
+        assert_eq!(history.all.len(), 3);
+        assert_eq!(history.filtered.len(), 2);
+        let leaf = &history.leaves[1];
+        assert_eq!(history.leaf_msg(leaf), "second, key: other value");
+        assert_eq!(leaf.kv[0].key, "key");
+    }
+
+    #[test]
+    fn keys_past_interning_limit_fall_back() {
+        // Longer than everything that may be interned, so that it's rejected without using up
+        // the limit for other tests
+        let key = &*Box::leak("k".repeat(2 * 1024 * 1024).into_boxed_str());
+        let mut console = Builder::new().build();
+        log(&mut console, "msg", Pair(key, "value"));
+
+        let loaded = reload(&console);
+        let leaf = &loaded.history.leaves[0];
+        let kv = &leaf.kv[0];
+        assert_eq!(kv.key, FALLBACK_KEY);
+        assert_eq!(
+            &loaded.history.leaf_msg(leaf)[kv.start as usize..kv.end as usize],
+            format!("{key}: value")
+        );
+    }
+
+    #[test]
+    fn invalid_sessions_are_rejected() {
+        let mut console = Builder::new().build();
+        log(&mut console, "msg", Pair("key", "value"));
+        let mut data = Vec::new();
+        console.save_session(&mut data).unwrap();
+
+        for len in 0..data.len() {
+            assert!(load(&data[..len]).is_err());
+        }
+        for i in MAGIC.len()..data.len() {
+            let mut data = data.clone();
+            data[i] ^= 0xFF;
+            // Corrupted strings can still be valid, but loading mustn't panic
+            let _ = load(&data[..]);
+        }
+    }
+}
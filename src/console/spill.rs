@@ -0,0 +1,503 @@
+use super::{
+    history::{source_hidden, History, Leaf, NodeId, ValueKind, NO_SOURCE},
+    FilterData,
+};
+use crate::binary::{
+    capacity_hint, invalid_data, read_string, read_u32, read_u8, write_str, write_u32, write_u8,
+};
+use slog::Level;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem::take,
+    ops::Range,
+    path::PathBuf,
+};
+
+const GROUP_TAG: u8 = 0xFF;
+const ROW_HEADER_LEN: usize = 13;
+
+#[derive(Clone)]
+pub struct Row {
+    pub indent: u16,
+    pub level: Option<Level>,
+    pub source: u16,
+    pub text: String,
+}
+
+/// A key-value pair of a spilled record, with the value stored as text inside the row's text.
+pub struct RowKv {
+    pub key: String,
+    pub kind: ValueKind,
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The parts of a spilled record that are only read back for exports: the length of the message
+/// part of its text, and its key-value pairs.
+#[derive(Default)]
+pub struct RowDetails {
+    pub msg_len: u32,
+    pub kv: Vec<RowKv>,
+}
+
+impl RowDetails {
+    fn write(leaf: &Leaf, writer: &mut impl Write) -> io::Result<()> {
+        write_u32(writer, leaf.msg_len)?;
+        write_u32(writer, leaf.kv.len() as u32)?;
+        for kv in leaf.kv.iter() {
+            write_str(writer, kv.key)?;
+            write_u8(writer, kv.kind as u8)?;
+            write_u32(writer, kv.start)?;
+            write_u32(writer, kv.end)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the details of the record whose text is `text`, checking that they fit it.
+    fn read(&mut self, reader: &mut impl Read, text: &str) -> io::Result<()> {
+        self.msg_len = read_u32(reader)?;
+        if !text.is_char_boundary(self.msg_len as usize) {
+            return Err(invalid_data("invalid message length"));
+        }
+        let kv_len = read_u32(reader)? as u64;
+        self.kv.clear();
+        self.kv.reserve(capacity_hint(kv_len));
+        for _ in 0..kv_len {
+            let key = read_string(reader)?;
+            let kind = *ValueKind::ALL
+                .get(read_u8(reader)? as usize)
+                .ok_or_else(|| invalid_data("invalid value kind"))?;
+            let start = read_u32(reader)?;
+            let end = read_u32(reader)?;
+            if start < self.msg_len
+                || start > end
+                || !text.is_char_boundary(start as usize)
+                || !text.is_char_boundary(end as usize)
+            {
+                return Err(invalid_data("invalid key-value pair span"));
+            }
+            self.kv.push(RowKv {
+                key,
+                kind,
+                start,
+                end,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn level_from_tag(tag: u8) -> Option<Level> {
+    Level::from_usize(tag as usize + 1)
+}
+
+/// Reads a row, along with its record's details if `details` is given; they're skipped otherwise.
+fn read_row(
+    reader: &mut impl Read,
+    buf: &mut Vec<u8>,
+    details: Option<&mut RowDetails>,
+) -> io::Result<Row> {
+    let mut header = [0; ROW_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    let details_len = u32::from_le_bytes([header[9], header[10], header[11], header[12]]) as usize;
+    buf.resize(len, 0);
+    reader.read_exact(buf)?;
+    let row = Row {
+        indent: u16::from_le_bytes([header[1], header[2]]),
+        level: if header[0] == GROUP_TAG {
+            None
+        } else {
+            level_from_tag(header[0])
+        },
+        source: u16::from_le_bytes([header[3], header[4]]),
+        text: String::from_utf8_lossy(buf).into_owned(),
+    };
+    buf.resize(details_len, 0);
+    reader.read_exact(buf)?;
+    if let (Some(details), Some(_)) = (details, row.level) {
+        details.read(&mut &buf[..], &row.text)?;
+    }
+    Ok(row)
+}
+
+struct PendingGroup {
+    row: usize,
+    text: String,
+    emitted: bool,
+}
+
+/// Incremental filter over spilled rows, which need to be matched in order to know which groups
+/// they're nested in.
+#[derive(Default)]
+struct RowFilter {
+    groups: Vec<PendingGroup>,
+    rows: Vec<usize>,
+}
+
+impl RowFilter {
+    fn clear(&mut self) {
+        self.groups.clear();
+        self.rows.clear();
+    }
+
+    fn push(&mut self, i: usize, row: &Row, filter_data: &FilterData) {
+        self.groups.truncate(row.indent as usize);
+
+        if row.level.is_none() {
+            self.groups.push(PendingGroup {
+                row: i,
+                text: row.text.clone(),
+                emitted: false,
+            });
+            return;
+        }
+
+        if source_hidden(filter_data.hidden_sources(), row.source)
+            || !row.text.contains(filter_data.msg_filter())
+            || !filter_data
+                .kv_filter()
+                .iter()
+                .all(|filter| self.groups.iter().any(|group| group.text.contains(filter)))
+        {
+            return;
+        }
+
+        for group in &mut self.groups {
+            if !group.emitted {
+                group.emitted = true;
+                self.rows.push(group.row);
+            }
+        }
+        self.rows.push(i);
+    }
+}
+
+struct Files {
+    writer: BufWriter<File>,
+    reader: File,
+}
+
+/// Append-only on-disk storage for leaves evicted from the history, along with the headers of
+/// the groups they were in, so that they can still be browsed and searched.
+pub struct Spill {
+    path: PathBuf,
+    files: Option<Files>,
+    len: u64,
+    rows: Vec<u64>,
+    written_groups: Vec<NodeId>,
+    filter: RowFilter,
+    /// Next row to match against the filters for the refilter in progress, if any.
+    refilter_next_row: Option<usize>,
+    /// The error that stopped records from being spilled, if any.
+    write_error: Option<String>,
+    page: (bool, Range<usize>, Vec<Row>),
+    buf: Vec<u8>,
+    /// Buffer the details of a record are written to before its row is.
+    details_buf: Vec<u8>,
+}
+
+impl Spill {
+    pub fn new(path: PathBuf) -> Self {
+        Spill {
+            path,
+            files: None,
+            len: 0,
+            rows: Vec::new(),
+            written_groups: Vec::new(),
+            filter: RowFilter::default(),
+            refilter_next_row: None,
+            write_error: None,
+            page: (false, 0..0, Vec::new()),
+            buf: Vec::new(),
+            details_buf: Vec::new(),
+        }
+    }
+
+    fn files(&mut self) -> io::Result<&mut Files> {
+        if self.files.is_none() {
+            let writer = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&self.path)?;
+            let reader = File::open(&self.path)?;
+            self.files = Some(Files {
+                writer: BufWriter::new(writer),
+                reader,
+            });
+        }
+        Ok(self.files.as_mut().unwrap())
+    }
+
+    pub fn len(&self, filtered: bool) -> usize {
+        if filtered {
+            self.filter.rows.len()
+        } else {
+            self.rows.len()
+        }
+    }
+
+    fn write_row(
+        &mut self,
+        indent: u16,
+        level: Option<Level>,
+        source: u16,
+        text: &str,
+        details: &[u8],
+        filter_data: &FilterData,
+    ) -> io::Result<()> {
+        let tag = level.map_or(GROUP_TAG, |level| (level.as_usize() - 1) as u8);
+        let writer = &mut self.files()?.writer;
+        writer.write_all(&[tag])?;
+        writer.write_all(&indent.to_le_bytes())?;
+        writer.write_all(&source.to_le_bytes())?;
+        writer.write_all(&(text.len() as u32).to_le_bytes())?;
+        writer.write_all(&(details.len() as u32).to_le_bytes())?;
+        writer.write_all(text.as_bytes())?;
+        writer.write_all(details)?;
+
+        let i = self.rows.len();
+        self.rows.push(self.len);
+        self.len += (ROW_HEADER_LEN + text.len() + details.len()) as u64;
+
+        // A refilter in progress gets to the row on its own
+        if filter_data.filtering_enabled() && self.refilter_next_row.is_none() {
+            self.filter.push(
+                i,
+                &Row {
+                    indent,
+                    level,
+                    source,
+                    text: text.to_string(),
+                },
+                filter_data,
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the error that stopped records from being spilled, if writing to the file failed.
+    #[inline]
+    pub fn write_error(&self) -> Option<&str> {
+        self.write_error.as_deref()
+    }
+
+    /// Appends the first `count` leaves of the history to the file, writing group headers
+    /// whenever a leaf's chain of groups differs from the previous one's.
+    ///
+    /// Once writing fails, the file's contents can't be relied on anymore, so nothing more is
+    /// written to it until it's cleared.
+    pub fn write_leaves(
+        &mut self,
+        history: &History,
+        count: usize,
+        filter_data: &FilterData,
+    ) -> io::Result<()> {
+        if self.write_error.is_some() {
+            return Ok(());
+        }
+        let result = self.append_leaves(history, count, filter_data);
+        if let Err(err) = &result {
+            self.write_error = Some(err.to_string());
+        }
+        result
+    }
+
+    fn append_leaves(
+        &mut self,
+        history: &History,
+        count: usize,
+        filter_data: &FilterData,
+    ) -> io::Result<()> {
+        let mut chain = Vec::new();
+        for leaf in &history.leaves[..count] {
+            if leaf.removed {
+                continue;
+            }
+
+            chain.clear();
+            let mut parent_id = leaf.parent;
+            while parent_id != NodeId::MAX {
+                chain.push(parent_id);
+                parent_id = history.groups[parent_id].parent;
+            }
+            chain.reverse();
+
+            let common_len = self
+                .written_groups
+                .iter()
+                .zip(&chain)
+                .take_while(|(a, b)| a == b)
+                .count();
+            self.written_groups.truncate(common_len);
+            for (indent, &id) in chain.iter().enumerate().skip(common_len) {
+                self.write_row(
+                    indent as u16,
+                    None,
+                    NO_SOURCE,
+                    &history.groups[id].kv_str,
+                    &[],
+                    filter_data,
+                )?;
+                self.written_groups.push(id);
+            }
+
+            let mut details = take(&mut self.details_buf);
+            details.clear();
+            RowDetails::write(leaf, &mut details)?;
+            let result = self.write_row(
+                chain.len() as u16,
+                Some(leaf.level),
+                leaf.source,
+                history.leaf_msg(leaf),
+                &details,
+                filter_data,
+            );
+            self.details_buf = details;
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Starts matching all spilled rows against the current filters again, clearing the filtered
+    /// rows; it's done by `continue_refilter`.
+    pub fn start_refilter(&mut self, filter_data: &FilterData) {
+        self.filter.clear();
+        self.page.1 = 0..0;
+        self.refilter_next_row =
+            (filter_data.filtering_enabled() && !self.rows.is_empty()).then_some(0);
+    }
+
+    #[cfg(feature = "async")]
+    #[inline]
+    pub fn refiltering(&self) -> bool {
+        self.refilter_next_row.is_some()
+    }
+
+    /// Returns the number of rows matched so far by the refilter in progress, if any, and the
+    /// total number of rows.
+    pub fn refilter_progress(&self) -> Option<(usize, usize)> {
+        Some((self.refilter_next_row?, self.rows.len()))
+    }
+
+    /// Matches up to `max_rows` more spilled rows against the filters for the refilter in
+    /// progress; returns whether it's finished. If the file can't be read back, no spilled rows
+    /// will match.
+    pub fn continue_refilter(&mut self, filter_data: &FilterData, max_rows: usize) -> bool {
+        let Some(start) = self.refilter_next_row else {
+            return true;
+        };
+        let end = start.saturating_add(max_rows).min(self.rows.len());
+
+        let files = self.files.as_mut().unwrap();
+        let result = (|| {
+            files.writer.flush()?;
+            files.reader.seek(SeekFrom::Start(self.rows[start]))?;
+            let mut reader = BufReader::new(&files.reader);
+            for i in start..end {
+                let row = read_row(&mut reader, &mut self.buf, None)?;
+                self.filter.push(i, &row, filter_data);
+            }
+            Ok::<_, io::Error>(())
+        })();
+        if result.is_err() {
+            self.filter.clear();
+            self.refilter_next_row = None;
+            return true;
+        }
+
+        if end == self.rows.len() {
+            self.refilter_next_row = None;
+            true
+        } else {
+            self.refilter_next_row = Some(end);
+            false
+        }
+    }
+
+    /// Returns the rows in the given range of either all spilled rows or the filtered ones,
+    /// reading them back from the file if they aren't cached already.
+    pub fn rows(&mut self, filtered: bool, range: Range<usize>) -> io::Result<&[Row]> {
+        if range.is_empty() {
+            return Ok(&[]);
+        }
+
+        if self.page.0 != filtered || self.page.1 != range {
+            self.page.1 = 0..0;
+            self.page.2.clear();
+
+            let files = self.files.as_mut().unwrap();
+            files.writer.flush()?;
+            if filtered {
+                for &i in &self.filter.rows[range.clone()] {
+                    files.reader.seek(SeekFrom::Start(self.rows[i]))?;
+                    self.page
+                        .2
+                        .push(read_row(&mut files.reader, &mut self.buf, None)?);
+                }
+            } else {
+                files.reader.seek(SeekFrom::Start(self.rows[range.start]))?;
+                let mut reader = BufReader::new(&files.reader);
+                for _ in range.clone() {
+                    self.page
+                        .2
+                        .push(read_row(&mut reader, &mut self.buf, None)?);
+                }
+            }
+
+            self.page.0 = filtered;
+            self.page.1 = range;
+        }
+        Ok(&self.page.2)
+    }
+
+    /// Calls `f` with each of either all spilled rows or the filtered ones in order, along with
+    /// the details of records (which are left as they were for group rows), reading them back from
+    /// the file one at a time.
+    pub fn for_each_row(
+        &mut self,
+        filtered: bool,
+        mut f: impl FnMut(&Row, &RowDetails) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let files = self.files.as_mut().unwrap();
+        files.writer.flush()?;
+        files.reader.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&files.reader);
+        let mut details = RowDetails::default();
+        // Filtered rows are in increasing order, so the whole file can be read through
+        let mut filtered_rows = self.filter.rows.iter().peekable();
+        for i in 0..self.rows.len() {
+            if filtered && filtered_rows.peek().is_none() {
+                break;
+            }
+            let row = read_row(&mut reader, &mut self.buf, Some(&mut details))?;
+            if filtered {
+                if filtered_rows.peek() != Some(&&i) {
+                    continue;
+                }
+                filtered_rows.next();
+            }
+            f(&row, &details)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets all spilled rows; the file will be truncated again on the next write.
+    pub fn clear(&mut self) {
+        self.files = None;
+        self.len = 0;
+        self.rows.clear();
+        self.written_groups.clear();
+        self.filter.clear();
+        self.refilter_next_row = None;
+        self.write_error = None;
+        self.page.1 = 0..0;
+        self.page.2.clear();
+    }
+}
@@ -0,0 +1,77 @@
+use super::{history::NO_SOURCE, Console};
+use slog::{OwnedKVList, Record};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A console shared between a [`SyncDrain`] and the code drawing it.
+///
+/// [`slog::Logger`] requires its drain to be `Send + Sync` (unless `slog`'s `nothreads` feature is
+/// enabled, which the async drain doesn't support), so single-threaded apps need to use an
+/// `Arc<Mutex<Console>>` too; it's never contended there.
+pub trait SharedConsole {
+    fn with_console<T>(&self, f: impl FnOnce(&mut Console) -> T) -> T;
+}
+
+impl SharedConsole for Arc<Mutex<Console>> {
+    #[inline]
+    fn with_console<T>(&self, f: impl FnOnce(&mut Console) -> T) -> T {
+        // A panic while processing a record can't leave the history in an inconsistent state
+        f(&mut self.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// [`slog::Drain`] that adds records to a shared console as they're logged.
+///
+/// History capacities are only applied once every `batch_size` records (or on
+/// [`flush`](Self::flush)), so the history can temporarily go over them by that many records.
+pub struct SyncDrain<C: SharedConsole> {
+    console: C,
+    batch_size: usize,
+}
+
+impl<C: SharedConsole> SyncDrain<C> {
+    #[inline]
+    pub fn new(console: C) -> Self {
+        SyncDrain {
+            console,
+            batch_size: 256,
+        }
+    }
+
+    #[inline]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    #[inline]
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    /// Applies history capacities to the records logged since the last batch was finished.
+    pub fn flush(&self) -> Result<(), slog::Error> {
+        self.console.with_console(|console| {
+            if console.unfinished_records != 0 {
+                console.finish_processing_records()
+            } else {
+                Ok(())
+            }
+        })
+    }
+}
+
+impl<C: SharedConsole> slog::Drain for SyncDrain<C> {
+    type Ok = ();
+    type Err = slog::Error;
+
+    fn log(&self, record: &Record, logger_values: &OwnedKVList) -> Result<(), slog::Error> {
+        self.console.with_console(|console| {
+            console.process_record(record, logger_values, NO_SOURCE)?;
+            console.unfinished_records += 1;
+            if console.unfinished_records >= self.batch_size {
+                console.finish_processing_records()?;
+            }
+            Ok(())
+        })
+    }
+}
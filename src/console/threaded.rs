@@ -0,0 +1,665 @@
+//! Running a console's record processing and filtering on a background thread, with the UI thread
+//! only drawing snapshots of the rows in view.
+
+use super::{history::NO_SOURCE, spill::Row, Console, ExportUi, LevelColors, Source, SourceId};
+use crate::async_drain::{OwnedRecord, Receiver};
+use crossbeam_channel::{never, select, Sender, TryRecvError};
+use imgui::{FontId, StyleColor, Ui};
+use std::{
+    fs::File,
+    io::BufWriter,
+    mem, panic,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Limits on the records processed before the worker checks for commands and snapshot requests.
+const MAX_BATCH_RECORDS: usize = 4096;
+const MAX_BATCH_DURATION: Duration = Duration::from_millis(8);
+
+/// Number of rows copied above and below the visible ones, so that scrolling doesn't show gaps
+/// while the next snapshot is being made.
+const ROW_MARGIN: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ViewRange {
+    start: usize,
+    len: usize,
+    locked_to_bottom: bool,
+}
+
+enum Command {
+    View(ViewRange),
+    MsgFilter(String),
+    KvFilter(Vec<String>),
+    SourceVisible(u16, bool),
+    Clear,
+    Run(Box<dyn FnOnce(&mut Console) + Send>),
+    /// Asks for a snapshot to be published once anything changes.
+    Snapshot,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    /// Total number of rows shown, including spilled ones.
+    len: usize,
+    /// Index of the first row in `rows`.
+    start: usize,
+    rows: Vec<Row>,
+    sources: Vec<Source>,
+    hidden_sources: Vec<bool>,
+    pending_records: usize,
+    refilter_progress: Option<f32>,
+    /// Number of filter or clear commands applied before the snapshot was made.
+    applied_commands: u64,
+    error: Option<String>,
+}
+
+/// The latest snapshot published by the worker; the worker and the view each own another one,
+/// which they swap with this one to publish and take it respectively.
+///
+/// Snapshots are only made when the view asks for one, at most once per frame, and the worker
+/// holds off on it until something has changed since the last one.
+struct Shared {
+    latest: Mutex<Snapshot>,
+    updated: AtomicBool,
+}
+
+struct Worker {
+    console: Console,
+    view: ViewRange,
+    applied_commands: u64,
+    error: Option<String>,
+    snapshot_requested: bool,
+    /// Whether anything shown in the view might have changed since the last snapshot.
+    changed: bool,
+}
+
+impl Worker {
+    fn apply(&mut self, command: Command) {
+        if let Command::Snapshot = command {
+            self.snapshot_requested = true;
+            return;
+        }
+        self.changed = true;
+        if !matches!(command, Command::View(_) | Command::Run(_)) {
+            self.applied_commands += 1;
+        }
+        match command {
+            Command::View(view) => self.view = view,
+            Command::MsgFilter(filter) => self.console.set_msg_filter(filter),
+            Command::KvFilter(filter) => self.console.set_kv_filter(filter),
+            Command::SourceVisible(source, visible) => {
+                self.console.set_source_visible(SourceId(source), visible)
+            }
+            Command::Clear => {
+                self.console.clear();
+                self.error = None;
+            }
+            Command::Run(f) => f(&mut self.console),
+            Command::Snapshot => unreachable!(),
+        }
+    }
+
+    fn fill_snapshot(&mut self, snapshot: &mut Snapshot) {
+        let len = self.console.row_count();
+        let start = if self.view.locked_to_bottom {
+            len.saturating_sub(self.view.len)
+        } else {
+            self.view.start.min(len)
+        };
+        snapshot.len = len;
+        snapshot.start = start;
+        snapshot.rows.clear();
+        let result = self.console.copy_rows(
+            start..start.saturating_add(self.view.len).min(len),
+            &mut snapshot.rows,
+        );
+        snapshot.error = match result {
+            Ok(()) => self.error.clone(),
+            Err(err) => Some(format!("Couldn't read spilled records: {err}")),
+        };
+        snapshot.sources.clone_from(&self.console.sources);
+        snapshot.hidden_sources.clear();
+        snapshot
+            .hidden_sources
+            .extend_from_slice(self.console.filter_data.hidden_sources());
+        snapshot.pending_records = self.console.pending_records;
+        snapshot.refilter_progress = self.console.refilter_progress();
+        snapshot.applied_commands = self.applied_commands;
+    }
+
+    fn process_records(&mut self, first: OwnedRecord, receiver: &Receiver) {
+        self.changed = true;
+        let result = self.console.process_owned_record(&first, NO_SOURCE);
+        receiver.pool.recycle(first);
+        let result = result.and_then(|()| {
+            self.console
+                .process_async_budgeted(receiver, MAX_BATCH_RECORDS, MAX_BATCH_DURATION)
+        });
+        if let Err(err) = result {
+            self.error = Some(format!("Couldn't process records: {err}"));
+        }
+    }
+
+    fn run(
+        mut self,
+        receiver: Receiver,
+        commands: crossbeam_channel::Receiver<Command>,
+        shared: Arc<Shared>,
+    ) -> Console {
+        let mut records = receiver.rx.clone();
+        let mut snapshot = Snapshot::default();
+        loop {
+            if self.console.refiltering() {
+                // Refilters are continued between batches instead of waiting for more changes, so
+                // that a new filter can cancel them right away
+                Console::continue_refilter(
+                    &mut self.console.history,
+                    &mut self.console.spill,
+                    &self.console.filter_data,
+                );
+                self.changed = true;
+                match records.try_recv() {
+                    Ok(record) => self.process_records(record, &receiver),
+                    Err(TryRecvError::Disconnected) => records = never(),
+                    Err(TryRecvError::Empty) => {}
+                }
+            } else {
+                select! {
+                    recv(commands) -> command => match command {
+                        Ok(command) => self.apply(command),
+                        Err(_) => return self.console,
+                    },
+                    recv(records) -> record => match record {
+                        Ok(record) => self.process_records(record, &receiver),
+                        // All senders were dropped, only commands can come in from now on
+                        Err(_) => records = never(),
+                    },
+                }
+            }
+            // View changes pile up while scrolling, only the last one matters
+            loop {
+                match commands.try_recv() {
+                    Ok(command) => self.apply(command),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return self.console,
+                }
+            }
+
+            if self.snapshot_requested && self.changed {
+                self.snapshot_requested = false;
+                self.changed = false;
+                self.fill_snapshot(&mut snapshot);
+                mem::swap(
+                    &mut *shared.latest.lock().unwrap_or_else(PoisonError::into_inner),
+                    &mut snapshot,
+                );
+                shared.updated.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// View of a [`Console`] that has been moved to a background thread, along with the receiver it
+/// processes records from.
+///
+/// Record processing, filtering and eviction all happen on the background thread, which publishes
+/// a snapshot of the rows in view when drawing asks for one and something has changed; drawing
+/// only copies the latest one, so refiltering a large history doesn't block the UI, which keeps
+/// showing the previous results until it's done.
+pub struct ThreadedConsole {
+    commands: Sender<Command>,
+    shared: Arc<Shared>,
+    worker: JoinHandle<Console>,
+    snapshot: Snapshot,
+    snapshot_requested: bool,
+    last_view: Option<ViewRange>,
+    sent_commands: u64,
+    export_ui: ExportUi,
+    export_result: Option<crossbeam_channel::Receiver<Result<(), String>>>,
+
+    show_options: bool,
+    msg_filter_buf: String,
+    kv_filter_buf: String,
+    pub locked_to_bottom: bool,
+    pub level_colors: LevelColors,
+}
+
+impl ThreadedConsole {
+    /// Moves `console` to a new background thread that processes the records sent to `receiver`.
+    pub fn new(console: Console, receiver: Receiver) -> Self {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let shared = Arc::new(Shared {
+            latest: Mutex::new(Snapshot::default()),
+            updated: AtomicBool::new(false),
+        });
+
+        let show_options = console.show_options();
+        let msg_filter_buf = console.msg_filter().to_string();
+        let kv_filter_buf = console.kv_filter().join(", ");
+        let locked_to_bottom = console.locked_to_bottom;
+        let level_colors = console.level_colors;
+
+        let worker = {
+            let shared = Arc::clone(&shared);
+            let worker = Worker {
+                console,
+                view: ViewRange {
+                    start: 0,
+                    len: 0,
+                    locked_to_bottom,
+                },
+                applied_commands: 0,
+                error: None,
+                snapshot_requested: false,
+                changed: true,
+            };
+            thread::spawn(move || worker.run(receiver, rx, shared))
+        };
+
+        let result = ThreadedConsole {
+            commands: tx,
+            shared,
+            worker,
+            snapshot: Snapshot::default(),
+            snapshot_requested: false,
+            last_view: None,
+            sent_commands: 0,
+            export_ui: ExportUi::new(),
+            export_result: None,
+
+            show_options,
+            msg_filter_buf,
+            kv_filter_buf,
+            locked_to_bottom,
+            level_colors,
+        };
+        // Make sure the sources show up before any records are received
+        result.run(|_| {});
+        result
+    }
+
+    fn send(&mut self, command: Command) {
+        if !matches!(
+            command,
+            Command::View(_) | Command::Run(_) | Command::Snapshot
+        ) {
+            self.sent_commands += 1;
+        }
+        // The worker only stops once the view is dropped, unless it panicked
+        let _ = self.commands.send(command);
+    }
+
+    /// Runs `f` on the console from the background thread, after any changes requested before;
+    /// this can be used to export or save it, or to add sources.
+    pub fn run(&self, f: impl FnOnce(&mut Console) + Send + 'static) {
+        let _ = self.commands.send(Command::Run(Box::new(f)));
+    }
+
+    /// Stops the background thread once it's done with the changes requested so far, and returns
+    /// the console.
+    pub fn into_inner(self) -> Console {
+        drop(self.commands);
+        self.worker
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+
+    #[inline]
+    pub fn show_options(&self) -> bool {
+        self.show_options
+    }
+
+    #[inline]
+    pub fn set_show_options(&mut self, value: bool) {
+        self.show_options = value;
+    }
+
+    pub fn set_msg_filter(&mut self, value: String) {
+        self.msg_filter_buf.clone_from(&value);
+        self.send(Command::MsgFilter(value));
+    }
+
+    pub fn set_kv_filter(&mut self, value: Vec<String>) {
+        self.kv_filter_buf = value.join(", ");
+        self.send(Command::KvFilter(value));
+    }
+
+    pub fn set_source_visible(&mut self, source: SourceId, visible: bool) {
+        self.send(Command::SourceVisible(source.0, visible));
+    }
+
+    pub fn clear(&mut self) {
+        self.send(Command::Clear);
+    }
+
+    fn update_snapshot(&mut self) {
+        if self.shared.updated.swap(false, Ordering::Acquire) {
+            mem::swap(
+                &mut *self
+                    .shared
+                    .latest
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+                &mut self.snapshot,
+            );
+            self.snapshot_requested = false;
+        }
+        if !self.snapshot_requested {
+            self.snapshot_requested = true;
+            self.send(Command::Snapshot);
+        }
+    }
+
+    /// Draws the export popup, running the export on the background thread.
+    fn draw_export_popup(&mut self, ui: &Ui) {
+        if let Some(export_result) = &self.export_result {
+            match export_result.try_recv() {
+                Ok(result) => self.export_ui.status = Some(result),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => {
+                    self.export_ui.status = Some(Err("The console stopped".to_string()))
+                }
+            }
+            if self.export_ui.status.is_some() {
+                self.export_result = None;
+            }
+        }
+
+        let Some(scope) =
+            self.export_ui
+                .draw_popup(ui, self.export_result.is_some(), self.level_colors.error)
+        else {
+            return;
+        };
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let (path, format) = (self.export_ui.path.clone(), self.export_ui.format);
+        self.run(move |console| {
+            let result = File::create(path)
+                .and_then(|file| console.export(BufWriter::new(file), format, scope))
+                .map_err(|err| err.to_string());
+            let _ = tx.send(result);
+        });
+        self.export_result = Some(rx);
+    }
+
+    pub fn draw_window(
+        &mut self,
+        ui: &Ui,
+        font: Option<FontId>,
+        text_spacing: f32,
+        text_padding: f32,
+        opened: &mut bool,
+    ) {
+        ui.window("Log").opened(opened).build(|| {
+            self.draw_options(ui);
+            ui.child_window("log_contents").build(|| {
+                let _font_token = font.map(|font| ui.push_font(font));
+                let _item_spacing =
+                    ui.push_style_var(imgui::StyleVar::ItemSpacing([0.0, text_spacing]));
+                let _frame_padding =
+                    ui.push_style_var(imgui::StyleVar::FramePadding([text_padding; 2]));
+                self.draw(ui);
+            });
+        });
+    }
+
+    pub fn draw_options(&mut self, ui: &Ui) {
+        if !self.show_options {
+            return;
+        }
+        self.update_snapshot();
+
+        let (frame_padding, item_spacing) = unsafe {
+            let style = ui.style();
+            (style.frame_padding, style.item_spacing)
+        };
+
+        ui.checkbox("Lock", &mut self.locked_to_bottom);
+
+        let clear_button_width = ui.calc_text_size("Clear")[0] + frame_padding[0] * 2.0;
+        let export_button_width = ui.calc_text_size("Export…")[0] + frame_padding[0] * 2.0;
+
+        ui.same_line();
+
+        let filter_field_width = (ui.content_region_avail()[0]
+            - clear_button_width
+            - export_button_width
+            - item_spacing[0] * 3.0)
+            * 0.5;
+
+        ui.set_next_item_width(filter_field_width);
+        if ui
+            .input_text("##msg_filter", &mut self.msg_filter_buf)
+            .hint("Message filter")
+            .build()
+        {
+            self.send(Command::MsgFilter(self.msg_filter_buf.clone()));
+        }
+
+        ui.same_line();
+        ui.set_next_item_width(filter_field_width);
+        if ui
+            .input_text("##kv_filter", &mut self.kv_filter_buf)
+            .hint("Group filter")
+            .build()
+        {
+            let filter = if self.kv_filter_buf.is_empty() {
+                Vec::new()
+            } else {
+                self.kv_filter_buf
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .collect()
+            };
+            self.send(Command::KvFilter(filter));
+        }
+
+        ui.same_line();
+        if ui.button_with_size("Clear", [clear_button_width, 0.0]) {
+            self.clear();
+        }
+
+        ui.same_line();
+        if ui.button_with_size("Export…", [export_button_width, 0.0]) {
+            if self.export_result.is_none() {
+                self.export_ui.status = None;
+            }
+            ui.open_popup("##export");
+        }
+        self.draw_export_popup(ui);
+
+        if !self.snapshot.sources.is_empty() {
+            ui.text("Sources:");
+            let mut changed = None;
+            for (i, source) in self.snapshot.sources.iter().enumerate() {
+                ui.same_line();
+                let mut visible = !self
+                    .snapshot
+                    .hidden_sources
+                    .get(i)
+                    .copied()
+                    .unwrap_or(false);
+                let _color = ui.push_style_color(StyleColor::Text, source.color);
+                if ui.checkbox(format!("{}##source_{i}", source.name), &mut visible) {
+                    changed = Some((i as u16, visible));
+                }
+            }
+            if let Some((source, visible)) = changed {
+                self.send(Command::SourceVisible(source, visible));
+            }
+        }
+
+        if self.snapshot.applied_commands < self.sent_commands {
+            ui.text_disabled("Updating…");
+        } else if let Some(progress) = self.snapshot.refilter_progress {
+            ui.text_disabled(format!("Filtering… {:.0}%", progress * 100.0));
+        }
+        if self.snapshot.pending_records != 0 {
+            ui.text_disabled(format!(
+                "Catching up… {} pending",
+                self.snapshot.pending_records
+            ));
+        }
+
+        ui.dummy([0.0, 6.0]);
+        ui.separator();
+        ui.dummy([0.0, 6.0]);
+    }
+
+    pub fn draw(&mut self, ui: &Ui) {
+        self.update_snapshot();
+
+        let len = self.snapshot.len;
+        let line_height = ui.frame_height_with_spacing() as f64;
+        let history_height = len as f64 * line_height;
+        let window_height = ui.window_size()[1] as f64;
+
+        if self.locked_to_bottom {
+            ui.set_scroll_y((history_height - window_height) as f32);
+        }
+
+        let top_y = ui.scroll_y() as f64;
+        let bot_y = top_y + window_height;
+
+        let y_offset = if self.locked_to_bottom {
+            (history_height - bot_y).max(0.0)
+        } else {
+            0.0
+        };
+
+        let start_i = (((top_y + y_offset) / line_height).floor() as usize).min(len);
+        let end_i = (((bot_y + y_offset) / line_height).ceil() as usize).min(len);
+
+        // Requested starts are aligned so that scrolling by a few rows doesn't need a new snapshot
+        let view = ViewRange {
+            start: if self.locked_to_bottom {
+                0
+            } else {
+                start_i.saturating_sub(ROW_MARGIN) / ROW_MARGIN * ROW_MARGIN
+            },
+            len: (window_height / line_height).ceil() as usize + ROW_MARGIN * 3,
+            locked_to_bottom: self.locked_to_bottom,
+        };
+        if self.last_view != Some(view) {
+            self.last_view = Some(view);
+            self.send(Command::View(view));
+        }
+
+        let (indent_spacing, frame_padding, item_spacing) = unsafe {
+            let style = ui.style();
+            (
+                style.indent_spacing,
+                style.frame_padding,
+                style.item_spacing,
+            )
+        };
+
+        let source_column_width = self
+            .snapshot
+            .sources
+            .iter()
+            .map(|source| ui.calc_text_size(&source.name)[0] + item_spacing[0])
+            .fold(0.0, f32::max);
+
+        ui.dummy([0.0, (start_i as f64 * line_height - y_offset) as f32]);
+
+        let line_y = |i: usize| (i as f64 * line_height - y_offset) as f32;
+
+        let mut error_shown = false;
+        for i in start_i..end_i {
+            let Some(row) = i
+                .checked_sub(self.snapshot.start)
+                .and_then(|i| self.snapshot.rows.get(i))
+            else {
+                // Rows that aren't in the snapshot yet are left empty
+                if let Some(error) = &self.snapshot.error {
+                    if !error_shown {
+                        error_shown = true;
+                        Console::draw_line(
+                            ui,
+                            i,
+                            line_y(i),
+                            0.0,
+                            error,
+                            self.level_colors.error,
+                            frame_padding,
+                        );
+                    }
+                }
+                continue;
+            };
+
+            Console::draw_line(
+                ui,
+                i,
+                line_y(i),
+                source_column_width + row.indent as f32 * indent_spacing,
+                &row.text,
+                row.level.map_or_else(
+                    || ui.style_color(StyleColor::Text),
+                    |level| self.level_colors.get(level),
+                ),
+                frame_padding,
+            );
+            if let Some(source) = self.snapshot.sources.get(row.source as usize) {
+                Console::draw_source(ui, line_y(i), source, frame_padding);
+            }
+        }
+
+        ui.set_cursor_pos([0.0, line_y(end_i)]);
+        ui.dummy([0.0, ((len - end_i) as f64 * line_height + y_offset) as f32]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{async_drain, console::Builder};
+    use slog::{info, o, Drain, Logger};
+    use std::time::Instant;
+
+    /// Draws frames (without a UI) until `done` returns true for the snapshot.
+    fn wait_for(view: &mut ThreadedConsole, mut done: impl FnMut(&Snapshot) -> bool) {
+        let start = Instant::now();
+        while !done(&view.snapshot) {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            view.update_snapshot();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn snapshots_are_only_published_on_request_after_changes() {
+        let (data, receiver) = async_drain::init();
+        let logger = Logger::root(async_drain::Drain::new(data).fuse(), o!());
+        let mut view = ThreadedConsole::new(Builder::new().build(), receiver);
+        view.send(Command::View(ViewRange {
+            start: 0,
+            len: 10,
+            locked_to_bottom: true,
+        }));
+
+        for i in 0..1000 {
+            info!(logger, "record {}", i);
+        }
+        wait_for(&mut view, |snapshot| snapshot.len == 1000);
+        assert_eq!(view.snapshot.start, 990);
+        assert_eq!(view.snapshot.rows.len(), 10);
+        assert!(view.snapshot.rows[9].text.ends_with("record 999"));
+
+        // Without any changes, requests are left pending
+        view.update_snapshot();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!view.shared.updated.load(Ordering::Acquire));
+
+        view.set_msg_filter("record 99".to_string());
+        wait_for(&mut view, |snapshot| snapshot.len == 11);
+        assert_eq!(view.into_inner().row_count(), 11);
+    }
+}
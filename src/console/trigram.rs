@@ -0,0 +1,247 @@
+use super::{
+    group_slab::GroupSlab,
+    history::{Leaf, NodeId},
+};
+use ahash::AHashMap as HashMap;
+use std::mem::size_of;
+
+type Trigram = [u8; 3];
+
+/// Lists of the IDs of the strings containing each trigram, in increasing order.
+#[derive(Default)]
+struct Postings {
+    ids: HashMap<Trigram, Vec<NodeId>>,
+    len: usize,
+    /// Number of strings indexed since the last compaction, including ones that were removed.
+    indexed: usize,
+}
+
+impl Postings {
+    fn clear(&mut self) {
+        self.ids.clear();
+        self.len = 0;
+        self.indexed = 0;
+    }
+
+    fn add(&mut self, id: NodeId, text: &str) {
+        for trigram in text.as_bytes().windows(3) {
+            let ids = self
+                .ids
+                .entry([trigram[0], trigram[1], trigram[2]])
+                .or_default();
+            // IDs are added in increasing order, so repeated trigrams are always at the end
+            if ids.last() != Some(&id) {
+                ids.push(id);
+                self.len += 1;
+            }
+        }
+        self.indexed += 1;
+    }
+
+    /// Replaces each ID with the one `f` maps it to, dropping the ones it maps to `None`; `f` must
+    /// keep the IDs in the same order.
+    fn remap(&mut self, mut f: impl FnMut(NodeId) -> Option<NodeId>, live: usize) {
+        self.len = 0;
+        self.ids.retain(|_, ids| {
+            ids.retain_mut(|id| match f(*id) {
+                Some(new_id) => {
+                    *id = new_id;
+                    true
+                }
+                None => false,
+            });
+            self.len += ids.len();
+            !ids.is_empty()
+        });
+        self.indexed = live;
+    }
+
+    fn candidates(&self, needle: &str) -> Option<Vec<NodeId>> {
+        if needle.len() < 3 {
+            return None;
+        }
+        let mut lists = Vec::with_capacity(needle.len() - 2);
+        for trigram in needle.as_bytes().windows(3) {
+            match self.ids.get(trigram) {
+                Some(ids) => lists.push(ids),
+                None => return Some(Vec::new()),
+            }
+        }
+        lists.sort_unstable_by_key(|ids| ids.len());
+        lists.dedup_by(|a, b| std::ptr::eq(*a, *b));
+
+        let mut result = lists[0].clone();
+        for ids in &lists[1..] {
+            if result.is_empty() {
+                break;
+            }
+            result.retain(|id| ids.binary_search(id).is_ok());
+        }
+        Some(result)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.ids.len() * size_of::<(Trigram, Vec<NodeId>)>() + self.len * size_of::<NodeId>()
+    }
+}
+
+/// Trigram index over leaf messages and group strings, which narrows down the ones a substring
+/// filter has to be checked against.
+///
+/// Removed leaves and groups are only dropped from it once they make up half of the indexed ones,
+/// so candidates can include IDs that aren't in the history anymore.
+#[derive(Default)]
+pub struct TrigramIndex {
+    leaves: Postings,
+    groups: Postings,
+}
+
+impl TrigramIndex {
+    pub fn clear(&mut self) {
+        self.leaves.clear();
+        self.groups.clear();
+    }
+
+    #[inline]
+    pub fn add_leaf(&mut self, id: NodeId, msg: &str) {
+        self.leaves.add(id, msg);
+    }
+
+    #[inline]
+    pub fn add_group(&mut self, id: NodeId, kv_str: &str) {
+        self.groups.add(id, kv_str);
+    }
+
+    /// Drops removed leaves and groups once there are enough of them.
+    pub fn prune(
+        &mut self,
+        leaves: &[Leaf],
+        leaf_base_id: NodeId,
+        live_leaves: usize,
+        groups: &GroupSlab,
+    ) {
+        if self.leaves.indexed > live_leaves * 2 {
+            self.leaves.remap(
+                |id| {
+                    id.checked_sub(leaf_base_id)
+                        .and_then(|i| leaves.get(i as usize))
+                        .is_some_and(|leaf| !leaf.removed)
+                        .then_some(id)
+                },
+                live_leaves,
+            );
+        }
+        if self.groups.indexed > groups.len() * 2 {
+            self.groups
+                .remap(|id| groups.contains(id).then_some(id), groups.len());
+        }
+    }
+
+    /// Changes the IDs of indexed leaves after the history's leaves have been renumbered, dropping
+    /// the ones `f` maps to `None`.
+    pub fn remap_leaves(&mut self, f: impl FnMut(NodeId) -> Option<NodeId>, live_leaves: usize) {
+        self.leaves.remap(f, live_leaves);
+    }
+
+    /// Returns the IDs of the leaves that can contain `needle`, in increasing order, or `None` if
+    /// it's too short to narrow them down.
+    #[inline]
+    pub fn leaf_candidates(&self, needle: &str) -> Option<Vec<NodeId>> {
+        self.leaves.candidates(needle)
+    }
+
+    /// Returns the IDs of the groups that can contain `needle`, in increasing order, or `None` if
+    /// it's too short to narrow them down.
+    #[inline]
+    pub fn group_candidates(&self, needle: &str) -> Option<Vec<NodeId>> {
+        self.groups.candidates(needle)
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.leaves.memory_usage() + self.groups.memory_usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::history::Group;
+
+    const WORDS: [&str; 6] = ["alpha", "beta", "gamma", "delta", "epsilon", "zeta"];
+
+    fn text(i: u64) -> String {
+        format!(
+            "{} {} {i}",
+            WORDS[i as usize % WORDS.len()],
+            WORDS[i as usize / 7 % WORDS.len()]
+        )
+    }
+
+    fn matching(ids: impl Iterator<Item = u64>, needle: &str) -> Vec<NodeId> {
+        ids.filter(|&i| text(i).contains(needle)).collect()
+    }
+
+    #[test]
+    fn candidates_include_every_match() {
+        let mut index = TrigramIndex::default();
+        for i in 0..1000 {
+            index.add_leaf(i, &text(i));
+        }
+        for needle in ["alpha", "ta ep", "a 12", "psilon zeta", "omega"] {
+            let candidates = index.leaf_candidates(needle).unwrap();
+            assert!(candidates.windows(2).all(|ids| ids[0] < ids[1]));
+            let expected = matching(0..1000, needle);
+            assert!(
+                expected.iter().all(|id| candidates.contains(id)),
+                "{needle}"
+            );
+            // Trigrams that are all in the needle can still be spread out in the text
+            assert!(candidates.len() <= expected.len() * 2 + 10, "{needle}");
+        }
+        assert_eq!(index.leaf_candidates("ab"), None);
+        assert_eq!(index.leaf_candidates("xyz"), Some(Vec::new()));
+        assert_eq!(index.group_candidates("alpha"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn remap_and_prune() {
+        let mut index = TrigramIndex::default();
+        for i in 0..1000 {
+            index.add_leaf(i, &text(i));
+        }
+        // Drop odd IDs and renumber the others, like compacting removed leaves out
+        index.remap_leaves(|id| (id % 2 == 0).then_some(id / 2), 500);
+        let expected: Vec<_> = matching(0..1000, "gamma")
+            .into_iter()
+            .filter(|id| id % 2 == 0)
+            .map(|id| id / 2)
+            .collect();
+        assert_eq!(index.leaf_candidates("gamma").unwrap(), expected);
+
+        let mut groups = GroupSlab::default();
+        for i in 0..100 {
+            index.add_group(i, &text(i));
+            groups.insert(
+                i,
+                Group {
+                    parent: NodeId::MAX,
+                    filtered_parent: NodeId::MAX,
+                    ref_count: 1,
+                    filtered_ref_count: 0,
+                    kv_str: text(i).into(),
+                },
+            );
+        }
+        let memory_usage = index.memory_usage();
+        groups.retain(|id, _| id >= 90);
+        index.prune(&[], 0, usize::MAX / 2, &groups);
+        assert_eq!(
+            index.group_candidates("alpha").unwrap(),
+            matching(90..100, "alpha")
+        );
+        assert!(index.memory_usage() < memory_usage);
+
+        index.clear();
+        assert_eq!(index.leaf_candidates("alpha"), Some(Vec::new()));
+    }
+}
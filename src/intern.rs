@@ -0,0 +1,48 @@
+use ahash::AHashSet;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+/// Maximum total length of the strings leaked by `try_intern`.
+const MAX_UNTRUSTED_BYTES: usize = 1024 * 1024;
+
+/// Key used in place of the ones `try_intern` couldn't intern anymore; the original key is kept at
+/// the start of the value instead.
+pub(crate) const FALLBACK_KEY: &str = "kv";
+
+fn strs() -> MutexGuard<'static, AHashSet<&'static str>> {
+    static STRS: OnceLock<Mutex<AHashSet<&'static str>>> = OnceLock::new();
+    STRS.get_or_init(Default::default).lock().unwrap()
+}
+
+/// Returns a `'static` string with the given contents (to be used as a key or record location),
+/// leaking it only the first time it's seen.
+#[cfg(feature = "async")]
+pub(crate) fn intern(value: &str) -> &'static str {
+    let mut strs = strs();
+    if let Some(&value) = strs.get(value) {
+        return value;
+    }
+    let value: &'static str = Box::leak(value.into());
+    strs.insert(value);
+    value
+}
+
+/// Like [`intern`], but for strings read from outside the process, which could all be different:
+/// returns `None` instead of leaking new ones once too many have been.
+pub(crate) fn try_intern(value: &str) -> Option<&'static str> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    // Only accessed with `STRS` locked
+    static UNTRUSTED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut strs = strs();
+    if let Some(&value) = strs.get(value) {
+        return Some(value);
+    }
+    let untrusted_bytes = UNTRUSTED_BYTES.load(Ordering::Relaxed) + value.len();
+    if untrusted_bytes > MAX_UNTRUSTED_BYTES {
+        return None;
+    }
+    UNTRUSTED_BYTES.store(untrusted_bytes, Ordering::Relaxed);
+    let value: &'static str = Box::leak(value.into());
+    strs.insert(value);
+    Some(value)
+}
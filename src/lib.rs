@@ -3,4 +3,6 @@
 #[cfg(feature = "async")]
 #[cfg_attr(feature = "nightly", doc(cfg(feature = "async")))]
 pub mod async_drain;
+mod binary;
 pub mod console;
+mod intern;